@@ -0,0 +1,110 @@
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+
+use metric_videos::events;
+use metric_videos::metrics::{MetricsStore, PlayerEvent, WatcherReport};
+use metric_videos::settings::{Media, Reports, Video};
+
+/// How many watchers report in each round, spread across the videos.
+const WATCHERS: usize = 4000;
+
+/// How many dashboards are connected to the event stream, for the runs with them.
+const DASHBOARDS: usize = 8;
+
+fn store() -> MetricsStore
+{
+    MetricsStore::new(&Media{videos: vec![
+        Video::from_url("http://example.com/a.mp4"),
+        Video::from_url("http://example.com/b.mp4"),
+        Video::from_url("http://example.com/c.mp4")
+    ]})
+}
+
+/// Have every watcher report once, split between `threads` threads all reporting at the same time.
+fn report_round(store: &MetricsStore, sessions: &[(String, &'static str)], threads: usize, rules: &Reports)
+{
+    let ip: IpAddr = "192.0.2.1".parse().unwrap();
+    let report = WatcherReport::new(50, PlayerEvent::Progress);
+    let report = &report;
+    thread::scope(|s| {
+        for chunk in sessions.chunks(sessions.len() / threads)
+        {
+            s.spawn(move || {
+                for (session, video) in chunk
+                {
+                    store.report(session, ip, video, report, rules).unwrap();
+                }
+            });
+        }
+    });
+}
+
+/*
+Throughput of watcher reports with thousands of watchers, as more threads report at once,
+alone, while a dashboard keeps taking snapshots, and while dashboards are being sent every event.
+*/
+fn concurrent_reports(c: &mut Criterion)
+{
+    let rules = Reports{min_interval_ms: 0, ..Reports::default()};
+    let videos = ["a", "b", "c"];
+    let sessions: Vec<(String, &'static str)> = (0..WATCHERS).map(|i| (format!("session{}", i), videos[i % videos.len()])).collect();
+
+    let mut group = c.benchmark_group("reports");
+    group.throughput(Throughput::Elements(WATCHERS as u64));
+    for threads in [1, 4, 16].iter()
+    {
+        let metrics = store();
+        group.bench_with_input(BenchmarkId::new("alone", threads), threads, |b, &threads| {
+            b.iter(|| report_round(&metrics, &sessions, threads, &rules));
+        });
+
+        let metrics = store();
+        let done = AtomicBool::new(false);
+        thread::scope(|s| {
+            s.spawn(|| {
+                while !done.load(Ordering::Relaxed)
+                {
+                    criterion::black_box(metrics.snapshot());
+                }
+            });
+            group.bench_with_input(BenchmarkId::new("with_snapshots", threads), threads, |b, &threads| {
+                b.iter(|| report_round(&metrics, &sessions, threads, &rules));
+            });
+            done.store(true, Ordering::Relaxed);
+        });
+
+        let metrics = store();
+        let done = AtomicBool::new(false);
+        thread::scope(|s| {
+            for _ in 0..DASHBOARDS
+            {
+                let (since, snapshot) = metrics.snapshot_since();
+                let mut rx = events::subscribe(&serde_json::to_string(&snapshot).unwrap(), since);
+                let done = &done;
+                s.spawn(move || {
+                    //read events as fast as a dashboard could, until the run is over
+                    while !done.load(Ordering::Relaxed)
+                    {
+                        match rx.try_next()
+                        {
+                            Ok(Some(event)) => {criterion::black_box(event);},
+                            Ok(None) => break,
+                            Err(_) => thread::yield_now()
+                        }
+                    }
+                });
+            }
+            group.bench_with_input(BenchmarkId::new("with_subscribers", threads), threads, |b, &threads| {
+                b.iter(|| report_round(&metrics, &sessions, threads, &rules));
+            });
+            done.store(true, Ordering::Relaxed);
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, concurrent_reports);
+criterion_main!(benches);
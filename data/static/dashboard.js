@@ -8,19 +8,61 @@ class Dashboard extends React.Component {
       lastFetch: 0
     };
     this.getUpdate = this.getUpdate.bind(this);
+    this.applyEvent = this.applyEvent.bind(this);
   }
 
   getUpdate() {
     const dash = this;
-    $.ajax("/api/metrics", {
-      method: "GET"
-    }).done(function (msg, textStatus, xhrObj) {
+    const source = new EventSource("/api/metrics/stream");
+    source.addEventListener("snapshot", function (e) {
       dash.setState({
-        metrics: msg,
+        metrics: JSON.parse(e.data),
         lastFetch: Math.floor(Date.now() / 1000)
       });
-    }).always(function () {
-      setTimeout(dash.getUpdate, 1000);
+    });
+
+    source.onmessage = function (e) {
+      dash.applyEvent(JSON.parse(e.data));
+    };
+  } //apply one watcher join/update/leave from the stream on top of the last snapshot
+
+
+  applyEvent(ev) {
+    this.setState(function (state) {
+      const index = state.metrics.videos.findIndex(function (v) {
+        return v.id === ev.video;
+      });
+
+      if (index < 0) {
+        return null;
+      }
+
+      const video = state.metrics.videos[index];
+
+      const watchers = Object.assign({}, video.watchers);
+
+      if (ev.type === "leave") {
+        delete watchers[ev.session];
+      } else {
+        watchers[ev.session] = Object.assign({}, watchers[ev.session], {
+          ip: ev.ip,
+          percent: ev.percent,
+          position: ev.position,
+          duration: ev.duration,
+          state: ev.state
+        });
+      }
+
+      const videos = state.metrics.videos.slice();
+      videos[index] = Object.assign({}, video, {
+        watchers: watchers
+      });
+      return {
+        metrics: Object.assign({}, state.metrics, {
+          videos: videos
+        }),
+        lastFetch: Math.floor(Date.now() / 1000)
+      };
     });
   }
 
@@ -28,6 +70,7 @@ class Dashboard extends React.Component {
     let totalActiveStreams = 0;
     let totalDistinctVideosBeingWatched = 0;
     let videosBeingWatchedByEachIp = {};
+    let totalByState = countStates([]);
     const displayLastUpdated = new Date(this.state.lastFetch * 1000).toUTCString();
     let vids = [];
     this.state.metrics.videos.forEach(function (singleVideoMetrics, index) {
@@ -38,24 +81,103 @@ class Dashboard extends React.Component {
         ++totalDistinctVideosBeingWatched;
       }
 
-      Object.keys(singleVideoMetrics.watchers).forEach(function (ip, watcherIndex) {
+      Object.values(singleVideoMetrics.watchers).forEach(function (watcher, watcherIndex) {
+        const ip = watcher.ip;
+
         if (!videosBeingWatchedByEachIp.hasOwnProperty(ip)) {
           videosBeingWatchedByEachIp[ip] = [];
         }
 
         videosBeingWatchedByEachIp[ip].push(index);
+        totalByState[watcher.state] = (totalByState[watcher.state] || 0) + 1;
       });
       vids.push( /*#__PURE__*/React.createElement(VideoMetrics, {
+        key: singleVideoMetrics.id,
         metrics: singleVideoMetrics,
-        videoName: videos[index]
+        videoName: videoTitle(singleVideoMetrics.id)
       }));
     });
     const totalDistinctIPsWatchingVideos = Object.keys(videosBeingWatchedByEachIp).length;
     return /*#__PURE__*/React.createElement("div", {
       className: "Dashboard"
-    }, /*#__PURE__*/React.createElement("dl", null, /*#__PURE__*/React.createElement("dt", null, "Active streams"), /*#__PURE__*/React.createElement("dd", null, totalActiveStreams), /*#__PURE__*/React.createElement("dt", null, "Distinct videos being watched"), /*#__PURE__*/React.createElement("dd", null, totalDistinctVideosBeingWatched), /*#__PURE__*/React.createElement("dt", null, "Distinct IPs watching videos"), /*#__PURE__*/React.createElement("dd", null, totalDistinctIPsWatchingVideos), /*#__PURE__*/React.createElement("dt", null, "This display last updated"), /*#__PURE__*/React.createElement("dd", null, displayLastUpdated)), vids);
+    }, /*#__PURE__*/React.createElement("dl", null, /*#__PURE__*/React.createElement("dt", null, "Active streams"), /*#__PURE__*/React.createElement("dd", null, totalActiveStreams), /*#__PURE__*/React.createElement("dt", null, "Distinct videos being watched"), /*#__PURE__*/React.createElement("dd", null, totalDistinctVideosBeingWatched), /*#__PURE__*/React.createElement("dt", null, "Distinct IPs watching videos"), /*#__PURE__*/React.createElement("dd", null, totalDistinctIPsWatchingVideos), /*#__PURE__*/React.createElement("dt", null, "Playing / paused / buffering"), /*#__PURE__*/React.createElement("dd", null, totalByState.playing, " / ", totalByState.paused, " / ", totalByState.buffering), /*#__PURE__*/React.createElement("dt", null, "This display last updated"), /*#__PURE__*/React.createElement("dd", null, displayLastUpdated)), /*#__PURE__*/React.createElement(ViewersChart, null), vids);
+  }
+
+} //chart of how many viewers there have been over the last hour, minute by minute, refreshed every minute
+
+
+class ViewersChart extends React.Component {
+  constructor(props) {
+    super(props);
+    this.state = {
+      series: null
+    };
+    this.fetchSeries = this.fetchSeries.bind(this);
   }
 
+  componentDidMount() {
+    this.fetchSeries();
+    this.timer = setInterval(this.fetchSeries, 60000);
+  }
+
+  componentWillUnmount() {
+    clearInterval(this.timer);
+  }
+
+  fetchSeries() {
+    const chart = this;
+    $.getJSON("/api/metrics/timeseries?step=60", function (series) {
+      chart.setState({
+        series: series
+      });
+    });
+  }
+
+  render() {
+    const series = this.state.series;
+
+    if (series === null || series.points.length === 0) {
+      return null;
+    }
+
+    const span = Math.max(series.to - series.from, 1);
+    const peak = Math.max.apply(null, series.points.map(function (p) {
+      return p.max;
+    }));
+    const points = series.points.map(function (p) {
+      return 100 * (p.t - series.from) / span + "," + (100 - 100 * p.max / Math.max(peak, 1));
+    }).join(" ");
+    return /*#__PURE__*/React.createElement("figure", {
+      className: "Viewers"
+    }, /*#__PURE__*/React.createElement("svg", {
+      viewBox: "0 0 100 100",
+      preserveAspectRatio: "none"
+    }, /*#__PURE__*/React.createElement("polyline", {
+      points: points
+    })), /*#__PURE__*/React.createElement("figcaption", null, "Most viewers each minute over the last hour, peaking at ", peak));
+  }
+
+} //count how many watchers are in each state their player can be in
+
+
+function countStates(watchers) {
+  let counts = {
+    playing: 0,
+    paused: 0,
+    buffering: 0
+  };
+  watchers.forEach(function (watcher) {
+    counts[watcher.state] = (counts[watcher.state] || 0) + 1;
+  });
+  return counts;
+} //look up the title of a video in the catalog, falling back to its id if it isn't there
+
+
+function videoTitle(id) {
+  const video = videos.find(function (v) {
+    return v.id === id;
+  });
+  return video === undefined ? id : video.title;
 }
 
 class VideoMetrics extends React.Component {
@@ -65,18 +187,78 @@ class VideoMetrics extends React.Component {
 
   render() {
     const watcherMap = this.props.metrics.watchers;
-    const ips = Object.keys(watcherMap);
-    const displayCount = ips.length > 0 ? ips.length + " viewer(s)" : "";
+    const sessions = Object.keys(watcherMap);
+    const states = countStates(Object.values(watcherMap));
+    const displayCount = sessions.length > 0 ? sessions.length + " viewer(s): " + states.playing + " playing, " + states.paused + " paused, " + states.buffering + " buffering" : "";
     let watchers = [];
-    ips.forEach(function (ip, index) {
+    sessions.forEach(function (session, index) {
       watchers.push( /*#__PURE__*/React.createElement(Watcher, {
-        ip: ip,
-        percent: watcherMap[ip].percent
+        key: session,
+        ip: watcherMap[session].ip,
+        percent: watcherMap[session].percent,
+        position: watcherMap[session].position,
+        duration: watcherMap[session].duration,
+        state: watcherMap[session].state
       }));
     });
     return /*#__PURE__*/React.createElement("fieldset", {
       className: "CountrySearchForm"
-    }, /*#__PURE__*/React.createElement("legend", null, this.props.videoName), displayCount, /*#__PURE__*/React.createElement("br", null), watchers);
+    }, /*#__PURE__*/React.createElement("legend", null, this.props.videoName), displayCount, /*#__PURE__*/React.createElement("br", null), watchers, /*#__PURE__*/React.createElement(RetentionChart, {
+      video: this.props.metrics.id
+    }), /*#__PURE__*/React.createElement(HeatmapChart, {
+      video: this.props.metrics.id
+    }));
+  }
+
+} //chart of how many viewers of a video were still watching at each point, from the sessions that have ended, refreshed every minute
+
+
+class RetentionChart extends React.Component {
+  constructor(props) {
+    super(props);
+    this.state = {
+      curve: null
+    };
+    this.fetchCurve = this.fetchCurve.bind(this);
+  }
+
+  componentDidMount() {
+    this.fetchCurve();
+    this.timer = setInterval(this.fetchCurve, 60000);
+  }
+
+  componentWillUnmount() {
+    clearInterval(this.timer);
+  }
+
+  fetchCurve() {
+    const chart = this;
+    $.getJSON("/api/videos/" + encodeURIComponent(this.props.video) + "/retention", function (curve) {
+      chart.setState({
+        curve: curve
+      });
+    });
+  }
+
+  render() {
+    const curve = this.state.curve;
+
+    if (curve === null || curve.sessions === 0) {
+      return null;
+    }
+
+    const points = curve.points.map(function (point) {
+      return point.percent + "," + (100 - point.share * 100);
+    }).join(" ");
+    const end = curve.duration == null ? "100%" : clock(curve.duration);
+    return /*#__PURE__*/React.createElement("figure", {
+      className: "Retention"
+    }, /*#__PURE__*/React.createElement("svg", {
+      viewBox: "0 0 100 100",
+      preserveAspectRatio: "none"
+    }, /*#__PURE__*/React.createElement("polyline", {
+      points: points
+    })), /*#__PURE__*/React.createElement("figcaption", null, "Still watching, by the furthest point each session reached, from 0 to ", end, ", over ", curve.sessions, " ended session(s)"));
   }
 
 }
@@ -84,5 +266,111 @@ class VideoMetrics extends React.Component {
 function Watcher(props) {
   return /*#__PURE__*/React.createElement("div", {
     className: "CountryListItem"
-  }, props.ip, /*#__PURE__*/React.createElement("br", null), props.percent, "%");
+  }, props.ip, /*#__PURE__*/React.createElement("br", null), props.percent, "% ", props.position == null ? "" : "(" + clock(props.position) + (props.duration == null ? "" : " of " + clock(props.duration)) + ") ", props.state);
+} //write a number of seconds like a clock, as h:mm:ss or m:ss
+
+
+function clock(seconds) {
+  const s = Math.floor(seconds);
+  const two = function (n) {
+    return (n < 10 ? "0" : "") + n;
+  };
+
+  const hours = Math.floor(s / 3600);
+  const minutes = Math.floor(s % 3600 / 60);
+  return (hours > 0 ? hours + ":" + two(minutes) : minutes) + ":" + two(s % 60);
+} //chart of which seconds of a video get played, played again and skipped, refreshed every minute
+
+
+class HeatmapChart extends React.Component {
+  constructor(props) {
+    super(props);
+    this.state = {
+      heatmap: null
+    };
+    this.fetchHeatmap = this.fetchHeatmap.bind(this);
+  }
+
+  componentDidMount() {
+    this.fetchHeatmap();
+    this.timer = setInterval(this.fetchHeatmap, 60000);
+  }
+
+  componentWillUnmount() {
+    clearInterval(this.timer);
+  }
+
+  fetchHeatmap() {
+    const chart = this;
+    $.getJSON("/api/videos/" + encodeURIComponent(this.props.video) + "/heatmap", function (heatmap) {
+      chart.setState({
+        heatmap: heatmap
+      });
+    });
+  }
+
+  render() {
+    const heatmap = this.state.heatmap;
+
+    if (heatmap === null) {
+      return null;
+    }
+
+    const segments = heatmap.segments;
+    const mostViews = Math.max.apply(null, segments.map(function (s) {
+      return s.views;
+    }).concat([0]));
+    const mostSkips = Math.max.apply(null, segments.map(function (s) {
+      return s.skips;
+    }).concat([0]));
+
+    if (mostViews === 0 && mostSkips === 0) {
+      return null;
+    } //plays rise from the bottom of the top band, with replays the part in another color, and skips shade the bottom band
+
+
+    let bars = [];
+    segments.forEach(function (s, second) {
+      if (s.views > 0) {
+        bars.push( /*#__PURE__*/React.createElement("rect", {
+          key: "v" + second,
+          className: "views",
+          x: second,
+          y: 80 - 80 * s.views / mostViews,
+          width: 1,
+          height: 80 * s.views / mostViews
+        }));
+      }
+
+      if (s.replays > 0) {
+        bars.push( /*#__PURE__*/React.createElement("rect", {
+          key: "r" + second,
+          className: "replays",
+          x: second,
+          y: 80 - 80 * s.replays / mostViews,
+          width: 1,
+          height: 80 * s.replays / mostViews
+        }));
+      }
+
+      if (s.skips > 0) {
+        bars.push( /*#__PURE__*/React.createElement("rect", {
+          key: "s" + second,
+          className: "skips",
+          x: second,
+          y: 85,
+          width: 1,
+          height: 15,
+          opacity: s.skips / mostSkips
+        }));
+      }
+    });
+    return /*#__PURE__*/React.createElement("figure", {
+      className: "Heatmap"
+    }, /*#__PURE__*/React.createElement("svg", {
+      viewBox: "0 0 " + Math.max(segments.length, 1) + " 100",
+      preserveAspectRatio: "none"
+    }, bars), /*#__PURE__*/React.createElement("figcaption", null, "Played, replayed and skipped, from 0 to ", clock(segments.length), ", with ", heatmap.seeks_forward, " jump(s) ahead and ", heatmap.seeks_backward, " back"));
+  }
+
 }
\ No newline at end of file
@@ -1,29 +1,147 @@
 'use strict';
 
-let currentVid = -1;
+let currentVid = null;
+let socket = null;
+let quality = newQuality();
 $(report);
+$(watchQuality); //before watchPlayer, so reports of player events include what they changed
+$(watchPlayer);
 
-/* Switch the player to another video by index.
+/* Report what happens in the player as it happens, as well as the regular progress reports.
+The browser's "waiting" is when playback stalls to buffer, and "playing" is when it gets going again.
+*/
+function watchPlayer()
+{
+    const events = {play: "play", playing: "play", pause: "pause", seeking: "seeking", ended: "ended", waiting: "buffering", error: "error"};
+    const player = $("#player");
+    Object.keys(events).forEach(function(name)
+    {
+        player.on(name, function() {sendReport(events[name]);});
+    });
+}
+
+/* Start over measuring how playback is going, for a new video.
+The times are kept out of what's sent, which is only the totals.
+*/
+function newQuality()
+{
+    return {rebuffers: 0, rebuffer_ms: 0, startup_ms: null, error: null, askedAt: null, stalledAt: null};
+}
+
+/* Measure how long playback takes to start, how often and how long it stalls afterwards, and what goes wrong.
+*/
+function watchQuality()
+{
+    const player = $("#player");
+    player.on("play", function()
+    {
+        if(quality.startup_ms === null) {quality.askedAt = performance.now();}
+    });
+    player.on("playing", function()
+    {
+        const now = performance.now();
+        if(quality.startup_ms === null && quality.askedAt !== null) {quality.startup_ms = Math.round(now - quality.askedAt);}
+        if(quality.stalledAt !== null) {quality.rebuffer_ms += Math.round(now - quality.stalledAt);}
+        quality.stalledAt = null;
+    });
+    player.on("waiting", function()
+    {
+        if(quality.startup_ms === null || quality.stalledAt !== null) {return;}
+        quality.rebuffers += 1;
+        quality.stalledAt = performance.now();
+    });
+    player.on("error", function()
+    {
+        const codes = {1: "MEDIA_ERR_ABORTED", 2: "MEDIA_ERR_NETWORK", 3: "MEDIA_ERR_DECODE", 4: "MEDIA_ERR_SRC_NOT_SUPPORTED"};
+        const error = player.prop("error");
+        quality.error = (error && codes[error.code]) || "MEDIA_ERR_UNKNOWN";
+    });
+}
+
+/* Switch the player to another video by its id in the catalog.
 */
 function switchVid(vidId)
 {
+    const video = videos.find(function(v) {return v.id === vidId;});
+    if(video === undefined) {return;}
     currentVid = vidId;
-    $("#player").attr("src",videos[vidId]);
+    quality = newQuality();
+    const player = $("#player");
+    player.attr("poster", video.poster || null);
+    player.empty();
+    video.sources.forEach(function(source)
+    {
+        const el = $("<source>").attr("src", source.url);
+        if(source.mime) {el.attr("type", source.mime);}
+        player.append(el);
+    });
+    player[0].load();
+    $("#description").text(video.description);
+    document.title = video.title + " - Metric Videos";
+    openSocket();
 }
 
-//send information about current video to server
+/* Open a WebSocket for reporting on the current video, replacing any previous one.
+Closing the old socket tells the server right away that we stopped watching that video.
+*/
+function openSocket()
+{
+    if(socket !== null) {socket.close();}
+    socket = null;
+    if(!("WebSocket" in window)) {return;}
+
+    const scheme = (location.protocol === "https:") ? "wss://" : "ws://";
+    const ws = new WebSocket(scheme + location.host + "/api/videos/" + currentVid + "/watcher?token=" + playbackToken);
+    ws.onclose = function() {if(socket === ws) {socket = null;}};
+    socket = ws;
+}
+
+//send information about current video to server every second
 function report()
 {
-    if(currentVid > -1)
-    {
-        const player = $("#player");
-        let duration = player.prop("duration");
-        let currentTime = player.prop("currentTime");
-        if(isNaN(duration)) {duration = 1;}
-        if(isNaN(currentTime)) {currentTime = 0;}
-        const percent = Math.floor((currentTime / duration) * 100);
-        const endpoint = "/api/watcher/" + currentVid + '/' + percent;
-        $.ajax(endpoint, {method: "POST"});
-    }
+    sendReport("progress");
     setTimeout(report, 1000);
 }
+
+/* Send the current position to the server, along with what just happened in the player,
+or "progress" if nothing did.
+*/
+function sendReport(event)
+{
+    if(currentVid === null) {return;}
+
+    const player = $("#player");
+    const duration = player.prop("duration");
+    let currentTime = player.prop("currentTime");
+    if(isNaN(currentTime)) {currentTime = 0;}
+    //until the video has loaded far enough to know how long it is, all there is to say is that we're at the start
+    const known = !isNaN(duration) && duration > 0;
+    const measured = {
+        startup_ms: quality.startup_ms,
+        rebuffers: quality.rebuffers,
+        rebuffer_ms: quality.rebuffer_ms,
+        width: player.prop("videoWidth") || null,
+        height: player.prop("videoHeight") || null,
+        error: quality.error
+    };
+    const report = {event: event, quality: measured, client: {player: "metric_videos web"}};
+    if(known)
+    {
+        report.position = Math.min(currentTime, duration);
+        report.duration = duration;
+    }else{
+        report.percent = 0;
+    }
+
+    if(socket !== null && socket.readyState === WebSocket.OPEN)
+    {
+        socket.send(JSON.stringify(report));
+    }else if(known){
+        //no socket available (yet), so fall back to one request per report
+        report.video = currentVid;
+        $.ajax("/api/v2/watcher?token=" + playbackToken, {method: "POST", contentType: "application/json", data: JSON.stringify(report)});
+    }else{
+        const endpoint = "/api/videos/" + currentVid + "/watcher/0?token=" + playbackToken + "&event=" + event;
+        $.ajax(endpoint, {method: "POST", contentType: "application/json", data: JSON.stringify(measured)});
+    }
+}
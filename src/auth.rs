@@ -0,0 +1,511 @@
+use actix_service::{Service, Transform};
+use actix_web::{dev::ServiceRequest, dev::ServiceResponse, Error, HttpMessage, HttpResponse, http::header, http::StatusCode};
+use actix_http::ResponseBuilder;
+use chrono::Utc;
+use futures::future::{ok, Either, Ready};
+use hmac::{Hmac, Mac, NewMac};
+use log::{warn, /*error, info, debug, trace, log, Level*/};
+use rand::RngCore;
+use rand::rngs::OsRng;
+use sha2::Sha256;
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use crate::settings::{SETTINGS, Settings};
+
+/// Name of the cookie that carries a login made through the login page.
+pub const LOGIN_COOKIE: &str = "mv_login";
+
+/// How long a playback token stays good after the player page hands it out.
+const PLAYBACK_TOKEN_HOURS: i64 = 24;
+
+/// How many rounds of PBKDF2 new password hashes get.
+const HASH_ROUNDS: u32 = 100_000;
+
+/// How long basic credentials are taken on trust once their password has been checked, so every request doesn't have to hash it again.
+const BASIC_TRUSTED_FOR: Duration = Duration::from_secs(60);
+
+/// Most basic credentials to remember as checked at once.
+const BASIC_TRUSTED_MAX: usize = 1000;
+
+/// Tag at the start of every password hash we make, so the scheme can change later without breaking old hashes.
+const HASH_SCHEME: &str = "pbkdf2-sha256";
+
+/**
+What someone is allowed to do. Each role can do everything the ones before it can.
+*/
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum Role
+{
+    /// Can watch the live dashboard and metrics
+    Viewer,
+    /// Can also look through viewing history
+    Analyst,
+    /// Can also change the video catalog
+    Admin
+}
+
+/**
+Who made a request, as established by `authenticate`. Requests that got through `RequireRole` carry one in their extensions.
+*/
+#[derive(Clone, Debug, PartialEq)]
+pub struct Identity
+{
+    pub name: String,
+    pub role: Role
+}
+
+/**
+Make a hash of a password, suitable for the `password_hash` of a user in the config.
+
+# Examples
+```
+use metric_videos::auth::{hash_password, verify_password};
+let hash = hash_password("correct horse");
+assert!(hash.starts_with("pbkdf2-sha256$"));
+assert!(verify_password("correct horse", &hash));
+assert!(!verify_password("battery staple", &hash));
+```
+*/
+pub fn hash_password(password: &str) -> String
+{
+    let mut salt = [0u8; 16];
+    OsRng.fill_bytes(&mut salt);
+    hash_with(password, &salt, HASH_ROUNDS)
+}
+
+/**
+Check a password against a hash made by `hash_password`.
+
+# Returns
+True if they match. A hash we can't make sense of never matches.
+*/
+pub fn verify_password(password: &str, hash: &str) -> bool
+{
+    let parts: Vec<&str> = hash.split('$').collect();
+    if parts.len() != 4 || parts[0] != HASH_SCHEME
+    {
+        return false;
+    }
+    let rounds = match parts[1].parse::<u32>()
+    {
+        Ok(r) if r > 0 => r,
+        _ => return false
+    };
+    match base64::decode_config(parts[2], base64::STANDARD_NO_PAD)
+    {
+        Ok(salt) => constant_eq(hash_with(password, &salt, rounds).as_bytes(), hash.as_bytes()),
+        Err(_) => false
+    }
+}
+
+fn hash_with(password: &str, salt: &[u8], rounds: u32) -> String
+{
+    let mut out = [0u8; 32];
+    pbkdf2::pbkdf2::<Hmac<Sha256>>(password.as_bytes(), salt, rounds, &mut out);
+    format!("{}${}${}${}", HASH_SCHEME, rounds, base64::encode_config(salt, base64::STANDARD_NO_PAD), base64::encode_config(&out, base64::STANDARD_NO_PAD))
+}
+
+/**
+Compare two secrets in a way that takes just as long no matter where they differ,
+so how long it takes doesn't give away how much of a guess was right.
+*/
+pub fn constant_eq(a: &[u8], b: &[u8]) -> bool
+{
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/**
+Sign a message with the server's secret key.
+
+# Returns
+The signature, in URL-safe base64.
+*/
+pub fn sign(message: &str) -> String
+{
+    let mut mac = Hmac::<Sha256>::new_varkey(&SIGNING_KEY).expect("HMAC accepts keys of any length");
+    mac.update(message.as_bytes());
+    base64::encode_config(&mac.finalize().into_bytes(), base64::URL_SAFE_NO_PAD)
+}
+
+/**
+Check a signature made by `sign`.
+*/
+pub fn verify_signature(message: &str, signature: &str) -> bool
+{
+    constant_eq(sign(message).as_bytes(), signature.as_bytes())
+}
+
+/**
+Stand in for something that identifies a person, like their address, with an id that can be matched up with itself
+but not turned back into what it stands for. It stays the same for as long as the `[auth]` secret does,
+which without one set is only until the app restarts.
+
+# Returns
+16 characters of URL-safe base64.
+*/
+pub fn anonymize(value: &str) -> String
+{
+    //signed as something no other signature covers, so these can't be passed off as one
+    let mut id = sign(&format!("anonymize {}", value));
+    id.truncate(16);
+    id
+}
+
+/**
+Make the value of a login cookie for a user. It names the user and when it stops being good, signed so it can't be forged.
+
+# Parameters
+- `name`: the user who logged in
+- `hours`: how long the login should last
+*/
+pub fn login_cookie_value(name: &str, hours: u32) -> String
+{
+    let expires = Utc::now().timestamp() + i64::from(hours) * 3600;
+    let payload = format!("{}.{}", base64::encode_config(name, base64::URL_SAFE_NO_PAD), expires);
+    format!("{}.{}", payload, sign(&payload))
+}
+
+/**
+Make a token for one playback, to be handed to the player along with the page.
+It carries a new session ID, and is signed so reports can't make up their own.
+
+# Examples
+```
+use metric_videos::auth::{playback_token, playback_session};
+let token = playback_token();
+let session = playback_session(&token).unwrap();
+assert!(token.starts_with(&session));
+let forged = format!("{}{}", "0".repeat(32), &token[32..]);
+assert_eq!(playback_session(&forged), None);
+```
+*/
+pub fn playback_token() -> String
+{
+    let mut id = [0u8; 16];
+    OsRng.fill_bytes(&mut id);
+    let session: String = id.iter().map(|b| format!("{:02x}", b)).collect();
+    let payload = format!("{}.{}", session, Utc::now().timestamp());
+    format!("{}.{}", payload, sign(&payload))
+}
+
+/**
+Check a token made by `playback_token`.
+
+# Returns
+The session ID it carries, or None if it's been tampered with or is too old.
+*/
+pub fn playback_session(token: &str) -> Option<String>
+{
+    let (payload, signature) = token.rsplit_once('.')?;
+    if !verify_signature(payload, signature)
+    {
+        return None;
+    }
+    let (session, issued) = payload.split_once('.')?;
+    if issued.parse::<i64>().ok()? + PLAYBACK_TOKEN_HOURS * 3600 < Utc::now().timestamp()
+    {
+        return None;
+    }
+    Some(session.to_owned())
+}
+
+/**
+Check a user's name and password against the config.
+A name that isn't a user's takes as long to turn away as a wrong password, so how long it takes doesn't give away who has an account.
+
+# Returns
+The user's identity if the password is right.
+*/
+pub fn check_password(settings: &Settings, name: &str, password: &str) -> Option<Identity>
+{
+    match settings.auth.users.iter().find(|u| u.name == name)
+    {
+        Some(user) if verify_password(password, &user.password_hash) => Some(Identity{name: user.name.clone(), role: user.role}),
+        Some(_) => None,
+        None => {
+            verify_password(password, &DUMMY_HASH);
+            None
+        }
+    }
+}
+
+/**
+Figure out who made a request, from a bearer token, HTTP basic credentials (if enabled), or a login cookie, in that order.
+Whatever is given first decides it: bad basic credentials don't fall back to the cookie.
+
+# Parameters
+- `req`: the request, either as handlers see it or as middleware does
+
+# Returns
+The identity of whoever made the request, or None if they didn't say or we don't believe them.
+*/
+pub fn authenticate<R: HttpMessage>(req: &R) -> Option<Identity>
+{
+    let settings = SETTINGS.get();
+
+    if let Some(value) = req.headers().get(header::AUTHORIZATION).and_then(|v| v.to_str().ok())
+    {
+        if let Some(token) = value.strip_prefix("Bearer ")
+        {
+            return check_token(&settings, token.trim());
+        }
+        if let Some(credentials) = value.strip_prefix("Basic ")
+        {
+            if !settings.auth.basic
+            {
+                return None;
+            }
+            //only a signature of the credentials is kept, so the passwords themselves aren't sitting in memory
+            let key = sign(&format!("basic {}", credentials.trim()));
+            if let Some(identity) = trusted_basic(&settings, &key)
+            {
+                return Some(identity);
+            }
+            let decoded = base64::decode(credentials.trim()).ok().and_then(|d| String::from_utf8(d).ok())?;
+            let (name, password) = decoded.split_once(':')?;
+            let identity = check_password(&settings, name, password)?;
+            trust_basic(&settings, key, &identity.name);
+            return Some(identity);
+        }
+    }
+
+    let cookie = req.cookie(LOGIN_COOKIE)?;
+    let (payload, signature) = cookie.value().rsplit_once('.')?;
+    if !verify_signature(payload, signature)
+    {
+        return None;
+    }
+    let (name, expires) = payload.split_once('.')?;
+    if expires.parse::<i64>().ok()? < Utc::now().timestamp()
+    {
+        return None;
+    }
+    let name = base64::decode_config(name, base64::URL_SAFE_NO_PAD).ok().and_then(|n| String::from_utf8(n).ok())?;
+    //the role comes from the config as it is now, so demoting or removing a user takes effect right away
+    let user = settings.auth.users.iter().find(|u| u.name == name)?;
+    Some(Identity{name: user.name.clone(), role: user.role})
+}
+
+/**
+Look up basic credentials that were checked recently.
+
+# Parameters
+- `key`: signature of the credentials, as the `Authorization` header gives them
+
+# Returns
+The identity they were checked as, with the role from the config as it is now,
+or None if they haven't been checked lately or the user's password has changed since.
+*/
+fn trusted_basic(settings: &Settings, key: &str) -> Option<Identity>
+{
+    let (name, hash) = {
+        let trusted = BASIC_TRUSTED.lock().ok()?;
+        let (name, hash, checked) = trusted.get(key)?;
+        if checked.elapsed() > BASIC_TRUSTED_FOR
+        {
+            return None;
+        }
+        (name.clone(), hash.clone())
+    };
+    let user = settings.auth.users.iter().find(|u| u.name == name && u.password_hash == hash)?;
+    Some(Identity{name: user.name.clone(), role: user.role})
+}
+
+/// Remember basic credentials as checked for a while, making room if too many are remembered by forgetting those too old to be used.
+fn trust_basic(settings: &Settings, key: String, name: &str)
+{
+    let hash = match settings.auth.users.iter().find(|u| u.name == name)
+    {
+        Some(user) => user.password_hash.clone(),
+        None => return
+    };
+    if let Ok(mut trusted) = BASIC_TRUSTED.lock()
+    {
+        if trusted.len() >= BASIC_TRUSTED_MAX
+        {
+            trusted.retain(|_, (_, _, checked)| checked.elapsed() <= BASIC_TRUSTED_FOR);
+            if trusted.len() >= BASIC_TRUSTED_MAX
+            {
+                trusted.clear();
+            }
+        }
+        trusted.insert(key, (name.to_owned(), hash, Instant::now()));
+    }
+}
+
+fn check_token(settings: &Settings, token: &str) -> Option<Identity>
+{
+    settings.auth.tokens.iter()
+        .find(|t| !t.token.is_empty() && constant_eq(token.as_bytes(), t.token.as_bytes()))
+        .map(|t| Identity{name: t.name.clone(), role: t.role})
+}
+
+/**
+Middleware that only lets through requests from someone with at least the given role.
+
+Anyone else gets 403 if we know who they are, or otherwise 401, except browsers asking for a page, which are sent to the login page.
+With `allow_anonymous` set in the config, everything short of the admin role is open to anyone.
+
+# Examples
+```
+use actix_web::{web, App};
+use metric_videos::auth::{RequireRole, Role};
+use metric_videos::resources::pages;
+let app = App::new().service(web::resource("/dashboard").wrap(RequireRole(Role::Viewer)).route(web::get().to(pages::dashboard)));
+```
+*/
+pub struct RequireRole(pub Role);
+
+impl<S> Transform<S> for RequireRole
+    where S: Service<Request = ServiceRequest, Response = ServiceResponse, Error = Error>, S::Future: 'static
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse;
+    type Error = Error;
+    type InitError = ();
+    type Transform = RequireRoleMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future
+    {
+        ok(RequireRoleMiddleware{service, role: self.0})
+    }
+}
+
+/**
+The service made by `RequireRole` around each one it guards.
+*/
+pub struct RequireRoleMiddleware<S>
+{
+    service: S,
+    role: Role
+}
+
+impl<S> Service for RequireRoleMiddleware<S>
+    where S: Service<Request = ServiceRequest, Response = ServiceResponse, Error = Error>, S::Future: 'static
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse;
+    type Error = Error;
+    type Future = Either<S::Future, Ready<Result<Self::Response, Self::Error>>>;
+
+    fn poll_ready(&mut self, cx: &mut Context) -> Poll<Result<(), Self::Error>>
+    {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: ServiceRequest) -> Self::Future
+    {
+        match authorize(&req, self.role)
+        {
+            Ok(identity) => {
+                if let Some(identity) = identity
+                {
+                    req.extensions_mut().insert(identity);
+                }
+                Either::Left(self.service.call(req))
+            },
+            Err(response) => Either::Right(ok(req.into_response(response)))
+        }
+    }
+}
+
+/**
+Decide whether a request may go ahead with something that needs the given role.
+
+# Returns
+Who made the request, if anyone in particular, when they may go ahead.
+
+# Errors
+The response to turn them away with.
+*/
+fn authorize(req: &ServiceRequest, role: Role) -> Result<Option<Identity>, HttpResponse>
+{
+    if let Some(identity) = authenticate(req)
+    {
+        if identity.role >= role
+        {
+            return Ok(Some(identity));
+        }
+        warn!("Refusing {} {} to {}, who is only a {:?}", req.method(), req.path(), identity.name, identity.role);
+        return Err(ResponseBuilder::new(StatusCode::FORBIDDEN).finish());
+    }
+
+    let settings = SETTINGS.get();
+    if role < Role::Admin && settings.auth.allow_anonymous
+    {
+        return Ok(None);
+    }
+
+    let wants_page = req.method() == actix_web::http::Method::GET && req.headers().get(header::ACCEPT)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.contains("text/html"));
+    if wants_page
+    {
+        let next = req.uri().path_and_query().map_or("/", |p| p.as_str());
+        let location = format!("/login?{}", serde_urlencoded::to_string([("next", next)]).unwrap_or_default());
+        return Err(ResponseBuilder::new(StatusCode::SEE_OTHER).set_header(header::LOCATION, location).finish());
+    }
+
+    let challenge = if settings.auth.basic {"Basic realm=\"metric_videos\", charset=\"UTF-8\""} else {"Bearer"};
+    Err(ResponseBuilder::new(StatusCode::UNAUTHORIZED).set_header(header::WWW_AUTHENTICATE, challenge).finish())
+}
+
+lazy_static!
+{
+    /// Hash that no password is checked against for real, made the same way as users' so checking against it takes as long
+    static ref DUMMY_HASH: String = hash_password("");
+
+    /// Basic credentials checked lately, by their signature: who they belong to, that user's password hash at the time, and when they were checked
+    static ref BASIC_TRUSTED: Mutex<HashMap<String, (String, String, Instant)>> = Mutex::new(HashMap::new());
+
+    /// Key for signing login cookies and anything else we hand out and need to trust when it comes back
+    static ref SIGNING_KEY: Vec<u8> = {
+        let secret = &SETTINGS.get().auth.secret;
+        if secret.is_empty()
+        {
+            let mut key = vec![0u8; 32];
+            OsRng.fill_bytes(&mut key);
+            key
+        }else{
+            secret.as_bytes().to_vec()
+        }
+    };
+}
+
+/*
+Test those functions which weren't able to have good tests as part of their
+example usage in the docs, but are still possible to unit-test
+*/
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    // auth::verify_password()
+    #[test]
+    fn password_hash_format()
+    {
+        let hash = hash_with("hunter2", b"saltsaltsaltsalt", 10);
+        assert!(hash.starts_with("pbkdf2-sha256$10$"));
+        assert!(verify_password("hunter2", &hash));
+        assert!(!verify_password("hunter3", &hash));
+        assert!(!verify_password("hunter2", &hash.replacen("$10$", "$11$", 1)));
+        assert!(!verify_password("hunter2", "hunter2"));
+        assert!(!verify_password("", ""));
+    }
+
+    // auth::Role ordering
+    #[test]
+    fn role_order()
+    {
+        assert!(Role::Viewer < Role::Analyst && Role::Analyst < Role::Admin);
+        let r: Role = serde_json::from_str("\"analyst\"").unwrap();
+        assert_eq!(r, Role::Analyst);
+    }
+}
@@ -0,0 +1,23 @@
+use std::io::{self, BufRead};
+
+use metric_videos::auth::hash_password;
+
+/**
+Reads a password from standard input and prints its hash, for pasting into the `password_hash` of a user in the config.
+*/
+fn main()
+{
+    let mut password = String::new();
+    if let Err(e) = io::stdin().lock().read_line(&mut password)
+    {
+        eprintln!("Couldn't read password: {}", e);
+        std::process::exit(1);
+    }
+    let password = password.trim_end_matches(['\n', '\r']);
+    if password.is_empty()
+    {
+        eprintln!("Usage: echo 'the password' | hash_password");
+        std::process::exit(1);
+    }
+    println!("{}", hash_password(password));
+}
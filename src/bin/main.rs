@@ -1,41 +1,80 @@
 use actix_web::{web, App, HttpServer};
-use log::{/*error, warn,*/ info, /*debug, trace, log, Level*/};
-use std::thread;
+use chrono::Utc;
+use log::{/*error,*/ warn, info, /*debug, trace, log, Level*/};
 
-use metric_videos::resources::{pages,api};
-use metric_videos::settings::SETTINGS;
-use metric_videos::updater;
+use metric_videos::auth::{RequireRole, Role};
+use metric_videos::ratelimit::{RateLimit, Limiter};
+use metric_videos::resources::{pages,api,admin};
+use metric_videos::settings::{Command, SETTINGS};
+use metric_videos::{summary, updater};
 
 /**
 Main entry point.
 
-This starts the ongoing threads for the cache updater and the HTTP listener.
+This starts the HTTP listener and the updater task alongside it, and when the listener stops
+(on SIGTERM, after letting requests in progress finish) flushes what the updater was holding on to.
+Run with the `report` subcommand, it prints a day's summary instead.
 Note that before execution even gets here, the configuration and logger have already been set up by
 the lazy_static code in the settings module.
 
 # Returns
-Result, but only when actix-web fails to bind to the port we want to use for HTTP.
+Result, but only when actix-web fails to bind to the port we want to use for HTTP, or fails while running,
+or when the summary for the report can't be read.
 */
 #[actix_rt::main]
 async fn main() -> std::io::Result<()>
 {
-    info!("Starting metric_videos on {}", &SETTINGS.startup.listen_addr);
-
-    //Keep the DB updated while the app runs
-    thread::spawn(|| { updater::updater(); });
+    let settings = SETTINGS.get();
+    if let Command::Report(date) = Command::from_args()
+    {
+        print!("{}", summary::daily(date.unwrap_or_else(|| Utc::now().naive_utc().date()))?);
+        return Ok(());
+    }
+    info!("Starting metric_videos on {}", &settings.startup.listen_addr);
+    if settings.auth.users.is_empty() && settings.auth.tokens.is_empty() && !settings.auth.allow_anonymous
+    {
+        warn!("No users or tokens are set up under [auth], so nobody can see the dashboard until one is added");
+    }
 
     //Start the HTTP server
-    HttpServer::new(|| {
+    let server = HttpServer::new(|| {
         App::new()
             .route("/",                        web::get().to(pages::index))        // request for root: this delivers the main app page that users see
-            .route("/dashboard",               web::get().to(pages::dashboard))    // dashboard page that shows the metrics
-            .route("/api/metrics",             web::get().to(api::metrics))        // ajax calls to retrieve metrics
-            .route("/api/watcher/{vid}/{pct}", web::post().to(api::watcher))       // ajax calls for watcher updates: we split part of the path into args
+            .route("/login",                   web::get().to(pages::login))        // login form for the pages below that need one
+            .service(web::resource("/login").wrap(RateLimit(Limiter::Login)).route(web::post().to(pages::login_submit))) // login form submission: sets the login cookie
+            .route("/logout",                  web::post().to(pages::logout))      // clears the login cookie
+            .service(web::resource("/dashboard").wrap(RequireRole(Role::Viewer)).route(web::get().to(pages::dashboard)))              // dashboard page that shows the metrics
+            .service(web::resource("/api/metrics").wrap(RequireRole(Role::Viewer)).wrap(RateLimit(Limiter::Metrics)).route(web::get().to(api::metrics)))                // ajax calls to retrieve metrics
+            .service(web::resource("/api/metrics/stream").wrap(RequireRole(Role::Viewer)).wrap(RateLimit(Limiter::Metrics)).route(web::get().to(api::metrics_stream)))  // server-sent events with live watcher changes
+            .service(web::resource("/api/metrics/timeseries").wrap(RequireRole(Role::Viewer)).wrap(RateLimit(Limiter::Metrics)).route(web::get().to(api::timeseries)))  // how many watchers there were over time
+            .service(web::resource("/metrics").wrap(RequireRole(Role::Viewer)).wrap(RateLimit(Limiter::Metrics)).route(web::get().to(api::prometheus_metrics)))         // Prometheus scrape target
+            .service(web::resource("/api/summary").wrap(RequireRole(Role::Viewer)).wrap(RateLimit(Limiter::Metrics)).route(web::get().to(api::summary)))               // daily numbers: sessions, viewers, watch time, completion and peaks
+            .service(web::resource("/api/history").wrap(RequireRole(Role::Analyst)).wrap(RateLimit(Limiter::Metrics)).route(web::get().to(api::history)))               // past viewing sessions, filtered by the query string
+            .service(web::resource("/api/export/sessions").wrap(RequireRole(Role::Analyst)).wrap(RateLimit(Limiter::Metrics)).route(web::get().to(api::export_sessions))) // past viewing sessions as CSV or NDJSON, for spreadsheets
+            .service(web::resource("/api/videos/{id}/retention").wrap(RequireRole(Role::Viewer)).wrap(RateLimit(Limiter::Metrics)).route(web::get().to(api::retention)))  // where watchers of a video stopped watching
+            .service(web::resource("/api/videos/{id}/heatmap").wrap(RequireRole(Role::Viewer)).wrap(RateLimit(Limiter::Metrics)).route(web::get().to(api::heatmap)))      // which seconds of a video get watched, watched again and skipped
+            .service(web::resource("/api/videos/{id}/watcher/{pct}").wrap(RateLimit(Limiter::Watcher)).route(web::post().to(api::watcher)))          // ajax calls for watcher updates: we split part of the path into args
+            .service(web::resource("/api/videos/{id}/watcher").wrap(RateLimit(Limiter::Watcher)).route(web::get().to(api::watcher_socket)))          // WebSocket alternative to the above: one connection per watcher
+            .service(web::resource("/api/v2/watcher").wrap(RateLimit(Limiter::Watcher)).route(web::post().to(api::watcher_v2)))                        // watcher updates with everything in a JSON body
+            .service(web::resource("/api/watcher/{vid}/{pct}").wrap(RateLimit(Limiter::Watcher)).route(web::post().to(api::watcher_by_index)))       // deprecated: same as above, but by position in the catalog
+            .service(web::resource("/api/watcher/{vid}").wrap(RateLimit(Limiter::Watcher)).route(web::get().to(api::watcher_socket_by_index)))       // deprecated: same as above, but by position in the catalog
+            .service(web::scope("/api/admin").wrap(RequireRole(Role::Admin))
+                .route("/videos",              web::post().to(admin::add_video))      // add a video to the catalog
+                .route("/videos/{id}",         web::put().to(admin::update_video))    // replace a video in the catalog
+                .route("/videos/{id}",         web::delete().to(admin::delete_video)) // remove a video from the catalog
+            )
             .service(actix_files::Files::new("/static", "static").disable_content_disposition())   // serve static files from given dir
             .default_service(web::route().to(pages::notfound))                     // where to go when nothing else matches
     })
-    .bind(&SETTINGS.startup.listen_addr)?
-    .run()
-    .await
+    .bind(&settings.startup.listen_addr)?
+    .run();
+
+    //Keep the metrics updated while the server runs. It's dropped along with the runtime when we return.
+    actix_rt::spawn(updater::updater());
+
+    server.await?;
+    info!("Server stopped, shutting down");
+    updater::shutdown();
+    Ok(())
 }
 
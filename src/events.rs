@@ -0,0 +1,336 @@
+use bytes::Bytes;
+use futures::channel::mpsc::{channel, Receiver, Sender};
+use log::{error, /*warn, info,*/ debug, /*trace, log, Level*/};
+
+use std::collections::VecDeque;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::{self, SyncSender, TrySendError};
+use std::thread;
+
+use crate::metrics::PlayerState;
+
+/// How many events may queue up for one slow client before we give up on it.
+const CLIENT_BUFFER: usize = 256;
+
+/// How many of the latest events are kept for catching up dashboards on what came in while their snapshot was being made.
+const REPLAY_SIZE: usize = 16_384;
+
+/// How many messages may wait for the dispatcher before new ones are dropped, and dashboards resynced once there's room.
+const QUEUE_SIZE: usize = 65_536;
+
+/**
+A change to the set of live watchers, as pushed to dashboards over the event stream.
+*/
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum WatcherEvent
+{
+    Join{video: String, session: String, ip: IpAddr, percent: u8, position: Option<f64>, duration: Option<f64>, state: PlayerState},
+    Update{video: String, session: String, ip: IpAddr, percent: u8, position: Option<f64>, duration: Option<f64>, state: PlayerState},
+    Leave{video: String, session: String}
+}
+
+/**
+Fans out server-sent events to every connected dashboard.
+
+Each client gets its own bounded channel. Sending never blocks: a client that has disconnected,
+or fallen so far behind that its buffer is full, is dropped on the next send.
+The latest events are kept, so a client given a snapshot that was some time in the making can be sent whatever came in meanwhile.
+*/
+pub struct Broadcaster
+{
+    clients: Vec<Sender<Bytes>>,
+    /// The latest events and their sequence numbers, in the order they were published
+    recent: VecDeque<(u64, WatcherEvent)>,
+    /// One past the highest sequence number among the events no longer kept
+    forgotten_below: u64
+}
+
+impl Broadcaster
+{
+    pub fn new() -> Self
+    {
+        Broadcaster{clients: Vec::new(), recent: VecDeque::new(), forgotten_below: 0}
+    }
+
+    /**
+    Register a new client, sending it a snapshot followed by every event since the snapshot was begun.
+
+    # Parameters
+    - `client`: the client's channel
+    - `snapshot`: JSON of the full state, as taken by `MetricsStore::snapshot_since`
+    - `since`: sequence number of the first event that may not be in the snapshot
+
+    # Returns
+    False if some of the events since then are no longer kept, in which case the client needs resyncing.
+    */
+    pub fn add(&mut self, mut client: Sender<Bytes>, snapshot: &str, since: u64) -> bool
+    {
+        let (message, complete) = self.catch_up(snapshot, since);
+        if client.try_send(message).is_ok()
+        {
+            self.clients.push(client);
+        }
+        complete
+    }
+
+    /**
+    Send a fresh snapshot to every client, followed by every event since it was begun, for when the state has changed in some way
+    the incremental events can't express, like videos being added to or removed from the catalog.
+
+    # Returns
+    False if some of the events since then are no longer kept, in which case the clients need resyncing again.
+    */
+    pub fn resync(&mut self, snapshot: &str, since: u64) -> bool
+    {
+        let (message, complete) = self.catch_up(snapshot, since);
+        self.send_raw(message);
+        complete
+    }
+
+    /**
+    Send a watcher event to every client, and keep it for any that are caught up later.
+    */
+    pub fn send(&mut self, sequence: u64, event: WatcherEvent)
+    {
+        if !self.clients.is_empty()
+        {
+            if let Some(json) = event_json(&event)
+            {
+                self.send_raw(Bytes::from(json));
+            }
+        }
+        if self.recent.len() == REPLAY_SIZE
+        {
+            if let Some((forgotten, _)) = self.recent.pop_front()
+            {
+                self.forgotten_below = self.forgotten_below.max(forgotten + 1);
+            }
+        }
+        self.recent.push_back((sequence, event));
+    }
+
+    /**
+    Send an SSE comment line to every client. This keeps idle connections open through proxies,
+    and lets us notice disconnected clients even when nothing is happening.
+    */
+    pub fn ping(&mut self)
+    {
+        self.send_raw(Bytes::from_static(b": ping\n\n"));
+    }
+
+    /// A snapshot and the events since it was begun, as one chunk so it takes up only one place in a client's buffer, and whether none of those events are missing.
+    fn catch_up(&self, snapshot: &str, since: u64) -> (Bytes, bool)
+    {
+        let mut message = format!("event: snapshot\ndata: {}\n\n", snapshot);
+        for (_, event) in self.recent.iter().filter(|(sequence, _)| *sequence >= since)
+        {
+            if let Some(json) = event_json(event)
+            {
+                message.push_str(&json);
+            }
+        }
+        (Bytes::from(message), since >= self.forgotten_below)
+    }
+
+    fn send_raw(&mut self, msg: Bytes)
+    {
+        self.clients.retain_mut(|c| c.try_send(msg.clone()).is_ok());
+    }
+}
+
+impl Default for Broadcaster {
+    fn default() -> Self {
+        Broadcaster::new()
+    }
+}
+
+/// A watcher event as an SSE message.
+fn event_json(event: &WatcherEvent) -> Option<String>
+{
+    match serde_json::to_string(event)
+    {
+        Ok(json) => Some(format!("data: {}\n\n", json)),
+        Err(e) => {
+            debug!("Couldn't serialize watcher event: {}", e);
+            None
+        }
+    }
+}
+
+/**
+What the dispatcher is asked to do, in the order it was asked.
+*/
+enum Message
+{
+    Event(u64, WatcherEvent),
+    Subscribe(Sender<Bytes>, String, u64),
+    Resync(String, u64),
+    Ping
+}
+
+/**
+Hand a message to the dispatcher, without waiting for it or any lock.
+If the queue is full the message is dropped, and dashboards are resynced once it has room again.
+*/
+fn publish(message: Message)
+{
+    if let Err(TrySendError::Full(_)) = QUEUE.try_send(message)
+    {
+        DROPPED.store(true, Ordering::Relaxed);
+    }
+}
+
+/**
+Send a watcher event to every dashboard, numbering it so dashboards given a snapshot can be caught up on what came in while it was made.
+Publish while still holding the watcher's lock: that keeps each watcher's events in order,
+and means a snapshot that copies the watcher afterwards already reflects every event numbered before it began.
+*/
+pub fn send(event: WatcherEvent)
+{
+    let sequence = SEQUENCE.fetch_add(1, Ordering::SeqCst);
+    publish(Message::Event(sequence, event));
+}
+
+/**
+The sequence number the next event will get. Read before a snapshot is begun, it's the first event that may not be in it.
+*/
+pub fn sequence() -> u64
+{
+    SEQUENCE.load(Ordering::SeqCst)
+}
+
+/**
+Register a new dashboard.
+
+# Parameters
+- `snapshot`: JSON of the full state, sent to this client only as a "snapshot" event
+  so it has something to apply the following incremental events to
+- `since`: sequence number of the first event that may not be in the snapshot. The client gets every event from there on.
+
+# Returns
+The receiving end of the client's channel, yielding SSE-formatted chunks.
+*/
+pub fn subscribe(snapshot: &str, since: u64) -> Receiver<Bytes>
+{
+    let (tx, rx) = channel(CLIENT_BUFFER);
+    publish(Message::Subscribe(tx, snapshot.to_owned(), since));
+    rx
+}
+
+/**
+Send a fresh snapshot to every dashboard, for when the state has changed in some way the incremental events can't express.
+
+# Parameters
+- `snapshot`: JSON of the full state
+- `since`: sequence number of the first event that may not be in the snapshot
+*/
+pub fn resync(snapshot: &str, since: u64)
+{
+    publish(Message::Resync(snapshot.to_owned(), since));
+}
+
+/**
+Keep idle dashboard connections open, and notice those that have gone.
+*/
+pub fn ping()
+{
+    publish(Message::Ping);
+}
+
+/**
+Whether any message has been dropped for want of room since this was last asked, in which case dashboards need resyncing.
+*/
+pub fn take_dropped() -> bool
+{
+    DROPPED.swap(false, Ordering::Relaxed)
+}
+
+/// Pass each message on to the dashboards, turning events into JSON here rather than in whatever published them.
+fn dispatch(queue: mpsc::Receiver<Message>)
+{
+    let mut broadcaster = Broadcaster::new();
+    for message in queue
+    {
+        let complete = match message
+        {
+            Message::Event(sequence, event) => {
+                broadcaster.send(sequence, event);
+                true
+            },
+            Message::Subscribe(client, snapshot, since) => broadcaster.add(client, &snapshot, since),
+            Message::Resync(snapshot, since) => broadcaster.resync(&snapshot, since),
+            Message::Ping => {
+                broadcaster.ping();
+                true
+            }
+        };
+        if !complete
+        {
+            DROPPED.store(true, Ordering::Relaxed);
+        }
+    }
+}
+
+static SEQUENCE: AtomicU64 = AtomicU64::new(0);
+
+static DROPPED: AtomicBool = AtomicBool::new(false);
+
+lazy_static!
+{
+    /// Queue of messages to the dashboards, read by a thread of its own so reports never wait on the dashboards
+    static ref QUEUE: SyncSender<Message> = {
+        let (tx, rx) = mpsc::sync_channel(QUEUE_SIZE);
+        if let Err(e) = thread::Builder::new().name(String::from("events")).spawn(move || dispatch(rx))
+        {
+            error!("Couldn't start sending events to dashboards: {}", e);
+        }
+        tx
+    };
+}
+
+/*
+Test catching dashboards up after a snapshot, without the dispatcher thread
+*/
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    fn leave(session: &str) -> WatcherEvent
+    {
+        WatcherEvent::Leave{video: String::from("intro"), session: session.to_owned()}
+    }
+
+    // events::Broadcaster::add()
+    #[test]
+    fn catch_up_after_snapshot()
+    {
+        let mut broadcaster = Broadcaster::new();
+        broadcaster.send(0, leave("a"));
+        //published in a different order from how they were numbered, as reports to different shards can be
+        broadcaster.send(2, leave("c"));
+        broadcaster.send(1, leave("b"));
+
+        let (tx, mut rx) = channel(CLIENT_BUFFER);
+        assert!(broadcaster.add(tx, "{}", 1));
+        let first = String::from_utf8(rx.try_next().unwrap().unwrap().to_vec()).unwrap();
+        assert!(first.starts_with("event: snapshot\ndata: {}\n\n"));
+        assert!(!first.contains("\"a\"") && first.find("\"c\"") < first.find("\"b\""));
+
+        broadcaster.send(3, leave("d"));
+        let next = String::from_utf8(rx.try_next().unwrap().unwrap().to_vec()).unwrap();
+        assert!(next.starts_with("data: ") && next.contains("\"d\""));
+
+        //once the events since a snapshot are no longer all kept, it can't be caught up
+        for i in 4..(REPLAY_SIZE as u64 + 4)
+        {
+            broadcaster.send(i, leave("e"));
+        }
+        let (tx, _rx) = channel(CLIENT_BUFFER);
+        assert!(!broadcaster.add(tx, "{}", 3));
+        let (tx, _rx) = channel(CLIENT_BUFFER);
+        assert!(broadcaster.add(tx, "{}", 4));
+    }
+}
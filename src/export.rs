@@ -0,0 +1,205 @@
+use chrono::NaiveDateTime;
+
+use std::collections::HashMap;
+
+use crate::auth;
+use crate::history::Session;
+
+/// Names of the columns of an export, in order, which are also the field names in NDJSON.
+pub const COLUMNS: [&str; 8] = ["video", "title", "viewer", "start", "end", "watch_time_secs", "max_percent", "completed"];
+
+/**
+What an export of sessions is written as.
+*/
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportFormat
+{
+    /// Comma-separated values, with a header line naming the columns
+    #[default]
+    Csv,
+    /// One JSON object per line
+    Ndjson
+}
+
+/**
+One viewing session, as it's exported.
+*/
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct ExportRow
+{
+    /// Catalog id of the video
+    pub video: String,
+    /// Title of the video in the catalog, or empty if it's no longer there
+    pub title: String,
+    /// Id standing in for the viewer's address, the same for every session from that address
+    pub viewer: String,
+    /// When the session started and ended, like "2020-09-13T12:26:40Z"
+    pub start: String,
+    pub end: String,
+    /// Seconds from the first report to the last
+    pub watch_time_secs: i64,
+    pub max_percent: u8,
+    /// Whether the viewer played through to the end
+    pub completed: bool
+}
+
+impl ExportRow
+{
+    /**
+    Turn a session into a row.
+
+    # Parameters
+    - `session`: the session from the history
+    - `title`: title of the video in the catalog
+    - `viewer`: id standing in for the viewer
+    */
+    pub fn new(session: &Session, title: &str, viewer: String) -> Self
+    {
+        ExportRow{
+            video: session.video.clone(),
+            title: title.to_owned(),
+            viewer,
+            start: timestamp(session.start),
+            end: timestamp(session.end),
+            watch_time_secs: (session.end - session.start).max(0),
+            max_percent: session.max_percent,
+            completed: session.completed
+        }
+    }
+}
+
+impl ExportFormat
+{
+    /// MIME type of an export in this format.
+    pub fn content_type(self) -> &'static str
+    {
+        match self
+        {
+            ExportFormat::Csv => "text/csv; charset=utf-8",
+            ExportFormat::Ndjson => "application/x-ndjson"
+        }
+    }
+
+    /// File extension for an export in this format.
+    pub fn extension(self) -> &'static str
+    {
+        match self
+        {
+            ExportFormat::Csv => "csv",
+            ExportFormat::Ndjson => "ndjson"
+        }
+    }
+
+    /// What goes at the start of an export in this format, before any rows, if anything.
+    pub fn header(self) -> Option<String>
+    {
+        match self
+        {
+            ExportFormat::Csv => Some(format!("{}\r\n", COLUMNS.join(","))),
+            ExportFormat::Ndjson => None
+        }
+    }
+
+    /**
+    Write one row, ending the line.
+
+    # Examples
+    ```
+    use metric_videos::export::{ExportFormat, ExportRow};
+    use metric_videos::history::Session;
+    let session = Session{video: String::from("intro"), video_index: None, session: String::new(), ip: None, start: 1_600_000_000, end: 1_600_000_090, max_percent: 100, completed: true};
+    let row = ExportRow::new(&session, "Hello, \"world\"", String::from("abc"));
+
+    let mut csv = String::new();
+    ExportFormat::Csv.write(&row, &mut csv);
+    assert_eq!(csv, "intro,\"Hello, \"\"world\"\"\",abc,2020-09-13T12:26:40Z,2020-09-13T12:28:10Z,90,100,true\r\n");
+
+    let mut ndjson = String::new();
+    ExportFormat::Ndjson.write(&row, &mut ndjson);
+    assert!(ndjson.starts_with(r#"{"video":"intro","title":"Hello, \"world\"","viewer":"abc","#) && ndjson.ends_with("}\n"));
+
+    //a title a spreadsheet would run as a formula is written as text
+    let row = ExportRow::new(&session, "=HYPERLINK(\"http://example.com\")", String::from("abc"));
+    let mut csv = String::new();
+    ExportFormat::Csv.write(&row, &mut csv);
+    assert!(csv.starts_with("intro,\"'=HYPERLINK(\"\"http://example.com\"\")\",abc,"));
+
+    //ids can't be formulas, and are written as they are so they still match the catalog
+    let session = Session{video: String::from("-intro"), ..session};
+    let row = ExportRow::new(&session, "-5 things", String::from("-abc"));
+    let mut csv = String::new();
+    ExportFormat::Csv.write(&row, &mut csv);
+    assert!(csv.starts_with("-intro,'-5 things,-abc,"));
+    ```
+    */
+    pub fn write(self, row: &ExportRow, out: &mut String)
+    {
+        match self
+        {
+            ExportFormat::Csv => {
+                let fields = [csv_field(&row.video), csv_text(&row.title), csv_field(&row.viewer), row.start.clone(), row.end.clone(),
+                    row.watch_time_secs.to_string(), row.max_percent.to_string(), row.completed.to_string()];
+                out.push_str(&fields.join(","));
+                out.push_str("\r\n");
+            },
+            ExportFormat::Ndjson => {
+                //a struct of strings, numbers and bools always serializes
+                out.push_str(&serde_json::to_string(row).unwrap_or_default());
+                out.push('\n');
+            }
+        }
+    }
+
+    /**
+    Write a batch of sessions, as rows with the title from the catalog and the viewer anonymized.
+
+    # Parameters
+    - `sessions`: the sessions from the history
+    - `titles`: title of each video in the catalog, by id
+    */
+    pub fn rows(self, sessions: &[Session], titles: &HashMap<String, String>) -> String
+    {
+        let mut out = String::new();
+        for session in sessions
+        {
+            //sessions from before addresses were kept are matched up by their session ID instead
+            let viewer = auth::anonymize(&session.ip.map_or_else(|| session.session.clone(), |ip| ip.to_string()));
+            let title = titles.get(&session.video).map_or("", String::as_str);
+            self.write(&ExportRow::new(session, title, viewer), &mut out);
+        }
+        out
+    }
+}
+
+/// Write a unix timestamp the way spreadsheets read it, in UTC.
+fn timestamp(t: i64) -> String
+{
+    NaiveDateTime::from_timestamp_opt(t, 0).map_or_else(|| t.to_string(), |d| d.format("%Y-%m-%dT%H:%M:%SZ").to_string())
+}
+
+/// Quote a CSV field if it needs it, doubling any quotes inside.
+fn csv_field(value: &str) -> String
+{
+    if value.contains([',', '"', '\r', '\n'])
+    {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    }else{
+        value.to_owned()
+    }
+}
+
+/**
+Write free text, like a title, as a CSV field.
+Text that a spreadsheet would take as a formula gets a `'` put in front, so it can't run anything when the export is opened.
+Ids are left to `csv_field`, since they can't hold a formula and have to match up with the catalog as they are.
+*/
+fn csv_text(value: &str) -> String
+{
+    if value.starts_with(['=', '+', '-', '@', '\t', '\r'])
+    {
+        csv_field(&format!("'{}", value))
+    }else{
+        csv_field(value)
+    }
+}
@@ -0,0 +1,153 @@
+/// Longest video we keep a heatmap for, in seconds, so a player claiming an absurd position can't fill memory.
+pub const MAX_SECONDS: usize = 12 * 3600;
+
+/// How many seconds a watcher may drift backwards between reports before it's taken as a seek, to allow for players rounding positions.
+const BACKWARD_SLACK_SECS: f64 = 1.0;
+
+/**
+Which parts of one video get watched, watched again and skipped, a second at a time.
+Each second is counted when a watcher's position moves onto it, so the counts are of watchers passing through rather than time spent.
+*/
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Heatmap
+{
+    /// Indexed by the second of the video
+    segments: Vec<Segment>,
+    seeks_forward: u64,
+    seeks_backward: u64
+}
+
+/**
+What happened to one second of a video.
+*/
+#[derive(Serialize, Clone, Copy, Debug, Default, PartialEq)]
+pub struct Segment
+{
+    /// How many times it was played
+    pub views: u64,
+    /// How many of those plays were by a watcher who had already played past it
+    pub replays: u64,
+    /// How many times a watcher jumped over it
+    pub skips: u64
+}
+
+impl Heatmap
+{
+    /**
+    Count how a watcher got from one position to the next.
+    Moving ahead no faster than they could have by playing counts the seconds in between as played.
+    Moving ahead any faster, or while their player says it's seeking, counts them as skipped.
+    Moving back is a seek too, and the seconds they go on to play from there count as replays.
+
+    # Parameters
+    - `from`, `to`: positions in seconds from successive reports
+    - `max_played`: the most seconds that could have been played in the time between the reports
+    - `seek`: whether the player said it was seeking
+    - `furthest`: how far the watcher has got by playing, in seconds, which is moved along if they play further
+
+    # Examples
+    ```
+    use metric_videos::heatmap::Heatmap;
+    let mut heatmap = Heatmap::default();
+    let mut furthest = 0.0;
+    heatmap.follow(0.0, 2.0, 2.0, false, &mut furthest);
+    heatmap.follow(2.0, 10.0, 2.0, false, &mut furthest);
+    heatmap.follow(10.0, 1.0, 2.0, true, &mut furthest);
+    heatmap.follow(1.0, 2.0, 2.0, false, &mut furthest);
+
+    let json = heatmap.report("intro", None);
+    assert_eq!((json.seeks_forward, json.seeks_backward, furthest), (1, 1, 2.0));
+    assert_eq!((json.segments[1].views, json.segments[1].replays), (2, 1));
+    assert_eq!(json.segments[5].skips, 1);
+    ```
+    */
+    pub fn follow(&mut self, from: f64, to: f64, max_played: f64, seek: bool, furthest: &mut f64)
+    {
+        if to > from && !seek && to - from <= max_played
+        {
+            for s in seconds(from, to)
+            {
+                let segment = self.segment(s);
+                segment.views += 1;
+                segment.replays += u64::from((s as f64) < *furthest);
+            }
+            *furthest = furthest.max(to);
+        }else if to > from{
+            self.seeks_forward += 1;
+            for s in seconds(from, to)
+            {
+                self.segment(s).skips += 1;
+            }
+        }else if from - to > BACKWARD_SLACK_SECS || (seek && to < from){
+            self.seeks_backward += 1;
+        }
+    }
+
+    /// Add the counts from another heatmap of the same video into this one.
+    pub fn add(&mut self, other: &Heatmap)
+    {
+        if self.segments.len() < other.segments.len()
+        {
+            self.segments.resize(other.segments.len(), Segment::default());
+        }
+        for (segment, more) in self.segments.iter_mut().zip(&other.segments)
+        {
+            segment.views += more.views;
+            segment.replays += more.replays;
+            segment.skips += more.skips;
+        }
+        self.seeks_forward += other.seeks_forward;
+        self.seeks_backward += other.seeks_backward;
+    }
+
+    /**
+    Turn the counts into what the heatmap endpoint serves.
+
+    # Parameters
+    - `video`: catalog id of the video
+    - `duration`: length of the video in seconds, if the catalog says. The segments run to its end, or else as far as anyone got.
+    */
+    pub fn report(&self, video: &str, duration: Option<u32>) -> HeatmapReport
+    {
+        let mut segments = self.segments.clone();
+        if let Some(d) = duration
+        {
+            segments.resize((d as usize).min(MAX_SECONDS), Segment::default());
+        }
+        HeatmapReport{video: video.to_owned(), duration, seeks_forward: self.seeks_forward, seeks_backward: self.seeks_backward, segments}
+    }
+
+    /// The counts for one second, making room for it if nobody has got that far before.
+    fn segment(&mut self, second: usize) -> &mut Segment
+    {
+        if self.segments.len() <= second
+        {
+            self.segments.resize(second + 1, Segment::default());
+        }
+        &mut self.segments[second]
+    }
+}
+
+/// The seconds whose start lies between two positions, counting the first but not the last, as far as we keep them.
+fn seconds(from: f64, to: f64) -> std::ops::Range<usize>
+{
+    let bound = |p: f64| (p.max(0.0).ceil() as usize).min(MAX_SECONDS);
+    bound(from)..bound(to)
+}
+
+/**
+Which parts of a video get watched, watched again and skipped, as served by the heatmap endpoint.
+*/
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct HeatmapReport
+{
+    /// Catalog id of the video
+    pub video: String,
+    /// Length of the video in seconds, if the catalog says
+    pub duration: Option<u32>,
+    /// How many times watchers jumped ahead, and back
+    pub seeks_forward: u64,
+    pub seeks_backward: u64,
+    /// One for each second of the video, starting from the first
+    pub segments: Vec<Segment>
+}
@@ -0,0 +1,335 @@
+use chrono::{NaiveDate, NaiveDateTime};
+use chrono::naive::{MAX_DATE, MIN_DATE};
+use log::{/*error, */warn, /*info, debug, trace, log, Level*/};
+
+use std::collections::BTreeMap;
+use std::fs::{self, OpenOptions};
+use std::io::{self, BufRead, BufReader, Lines, Write};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+
+use crate::settings::SETTINGS;
+
+/// Folder, relative to the working dir, where the history files are kept.
+const HISTORY_DIR: &str = "history";
+
+/**
+One viewing session: a single watcher's continuous stay on one video, from their first report until they were pruned.
+*/
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Session
+{
+    /// Catalog id of the video watched
+    #[serde(default)]
+    pub video: String,
+    /// Position of the video in the catalog, only found in sessions recorded before videos had ids
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub video_index: Option<usize>,
+    /// Session ID of the watcher. Empty for sessions recorded before watchers had IDs.
+    #[serde(default)]
+    pub session: String,
+    #[serde(default)]
+    pub ip: Option<IpAddr>,
+    pub start: i64,
+    pub end: i64,
+    pub max_percent: u8,
+    /// Whether the watcher played through to the end. Always false for sessions recorded before players sent events.
+    #[serde(default)]
+    pub completed: bool
+}
+
+/**
+Append finished sessions to the history store.
+
+Sessions are stored as one JSON object per line, in one file per UTC day (by the session's end time),
+so the files are never rewritten and a crash can lose at most the line being written.
+
+# Parameters
+- `sessions`: the sessions to record. May be empty, in which case nothing is touched.
+
+# Errors
+Any filesystem error from creating the history folder or appending to a day file.
+*/
+pub fn record(sessions: &[Session]) -> io::Result<()>
+{
+    record_in(Path::new(HISTORY_DIR), sessions)
+}
+
+/**
+Load every recorded session whose end time falls within the given range.
+
+# Parameters
+- `from`: earliest end time to include, as a unix timestamp
+- `to`: latest end time to include, as a unix timestamp
+
+# Returns
+The matching sessions, ordered by day file and then by the order they were recorded.
+Sessions recorded before videos had ids are given the id of the video now at their recorded position in the catalog.
+
+# Errors
+Any filesystem error from reading the history folder. Individual lines that can't be parsed are skipped with a warning.
+*/
+pub fn query(from: i64, to: i64) -> io::Result<Vec<Session>>
+{
+    let mut sessions = query_in(Path::new(HISTORY_DIR), from, to)?;
+    fill_video_ids(&mut sessions);
+    Ok(sessions)
+}
+
+/**
+Find the days that have any sessions recorded, for reading them a batch at a time with `SessionReader`.
+
+# Parameters
+- `first`, `last`: the range of UTC days to look in, including both
+
+# Returns
+The days, in order.
+
+# Errors
+Any filesystem error from reading the history folder.
+*/
+pub fn days(first: NaiveDate, last: NaiveDate) -> io::Result<Vec<NaiveDate>>
+{
+    days_in(Path::new(HISTORY_DIR), first, last)
+}
+
+/**
+Reads the sessions that ended on a list of days a batch at a time, a line at a time from each day file,
+so going through any amount of history only takes a batch's worth of memory.
+*/
+pub struct SessionReader
+{
+    dir: PathBuf,
+    days: std::vec::IntoIter<NaiveDate>,
+    /// The day file being read, and its lines not yet read
+    current: Option<(PathBuf, Lines<BufReader<fs::File>>)>
+}
+
+impl SessionReader
+{
+    /**
+    Get ready to read the sessions of some days, as found by `days`.
+    */
+    pub fn new(days: Vec<NaiveDate>) -> Self
+    {
+        SessionReader::in_dir(Path::new(HISTORY_DIR), days)
+    }
+
+    fn in_dir(dir: &Path, days: Vec<NaiveDate>) -> Self
+    {
+        SessionReader{dir: dir.to_owned(), days: days.into_iter(), current: None}
+    }
+
+    /**
+    Read the next batch of sessions, going on from one day to the next as each runs out.
+
+    # Parameters
+    - `max`: most sessions to read
+
+    # Returns
+    Up to `max` sessions, in the order they were recorded, with ids filled in the same way as `query` does. None once every day has been read.
+
+    # Errors
+    Any filesystem error from opening or reading a day file. Individual lines that can't be parsed are skipped with a warning.
+    */
+    pub fn next_batch(&mut self, max: usize) -> io::Result<Option<Vec<Session>>>
+    {
+        let mut batch = self.read_batch(max)?;
+        if let Some(sessions) = &mut batch
+        {
+            fill_video_ids(sessions);
+        }
+        Ok(batch)
+    }
+
+    /// Read the next batch of sessions as they were recorded.
+    fn read_batch(&mut self, max: usize) -> io::Result<Option<Vec<Session>>>
+    {
+        let mut batch = Vec::new();
+        while batch.len() < max
+        {
+            let (path, lines) = match &mut self.current
+            {
+                Some(current) => current,
+                None => match self.days.next()
+                {
+                    Some(day) => {
+                        let path = day_file(&self.dir, day);
+                        let lines = BufReader::new(fs::File::open(&path)?).lines();
+                        self.current.get_or_insert((path, lines))
+                    },
+                    None => break
+                }
+            };
+            match lines.next()
+            {
+                Some(line) => batch.extend(parse_line(path, &line?)),
+                None => self.current = None
+            }
+        }
+        Ok(if batch.is_empty() {None} else {Some(batch)})
+    }
+}
+
+/// Give sessions recorded before videos had ids the id of the video now at their recorded position in the catalog.
+fn fill_video_ids(sessions: &mut [Session])
+{
+    let settings = SETTINGS.get();
+    for s in sessions.iter_mut().filter(|s| s.video.is_empty())
+    {
+        if let Some(video) = s.video_index.and_then(|i| settings.media.videos.get(i))
+        {
+            s.video = video.id.clone();
+        }
+    }
+}
+
+/// The UTC day a unix timestamp falls on.
+pub fn day_of(timestamp: i64) -> NaiveDate
+{
+    match NaiveDateTime::from_timestamp_opt(timestamp, 0)
+    {
+        Some(t) => t.date(),
+        None => if timestamp < 0 {MIN_DATE} else {MAX_DATE}
+    }
+}
+
+fn day_file(dir: &Path, day: NaiveDate) -> PathBuf
+{
+    dir.join(format!("{}.jsonl", day.format("%Y-%m-%d")))
+}
+
+fn record_in(dir: &Path, sessions: &[Session]) -> io::Result<()>
+{
+    if sessions.is_empty()
+    {
+        return Ok(());
+    }
+    fs::create_dir_all(dir)?;
+
+    //group by day so each file is opened only once
+    let mut by_day: BTreeMap<NaiveDate, String> = BTreeMap::new();
+    for session in sessions
+    {
+        let line = serde_json::to_string(session)?;
+        let buf = by_day.entry(day_of(session.end)).or_default();
+        buf.push_str(&line);
+        buf.push('\n');
+    }
+
+    for (day, lines) in by_day
+    {
+        let mut file = OpenOptions::new().create(true).append(true).open(day_file(dir, day))?;
+        file.write_all(lines.as_bytes())?;
+    }
+    Ok(())
+}
+
+fn query_in(dir: &Path, from: i64, to: i64) -> io::Result<Vec<Session>>
+{
+    let mut out = Vec::new();
+    if from > to
+    {
+        return Ok(out);
+    }
+    for day in days_in(dir, day_of(from), day_of(to))?
+    {
+        out.append(&mut read_day_in(dir, day, from, to)?);
+    }
+    Ok(out)
+}
+
+fn days_in(dir: &Path, first_day: NaiveDate, last_day: NaiveDate) -> io::Result<Vec<NaiveDate>>
+{
+    let mut days: Vec<NaiveDate> = Vec::new();
+    if !dir.is_dir()
+    {
+        return Ok(days);
+    }
+    for entry in fs::read_dir(dir)?
+    {
+        let name = entry?.file_name();
+        let name = name.to_string_lossy();
+        if let Some(stem) = name.strip_suffix(".jsonl")
+        {
+            if let Ok(day) = NaiveDate::parse_from_str(stem, "%Y-%m-%d")
+            {
+                if day >= first_day && day <= last_day
+                {
+                    days.push(day);
+                }
+            }
+        }
+    }
+    days.sort();
+    Ok(days)
+}
+
+/// Read the sessions from one day file whose end time falls within the given range.
+fn read_day_in(dir: &Path, day: NaiveDate, from: i64, to: i64) -> io::Result<Vec<Session>>
+{
+    let mut out = Vec::new();
+    let path = day_file(dir, day);
+    let reader = BufReader::new(fs::File::open(&path)?);
+    for line in reader.lines()
+    {
+        out.extend(parse_line(&path, &line?).filter(|s| s.end >= from && s.end <= to));
+    }
+    Ok(out)
+}
+
+/// Read one line of a day file, warning about it if it isn't a session. Blank lines are skipped quietly.
+fn parse_line(path: &Path, line: &str) -> Option<Session>
+{
+    if line.is_empty()
+    {
+        return None;
+    }
+    match serde_json::from_str::<Session>(line)
+    {
+        Ok(s) => Some(s),
+        Err(e) => {
+            warn!("Skipping unreadable history line in {}: {}", path.display(), e);
+            None
+        }
+    }
+}
+
+/*
+Test the storage format against a scratch folder, since the public functions
+always work relative to the working dir
+*/
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    // history::record() and history::query()
+    #[test]
+    fn record_and_query()
+    {
+        let dir = std::env::temp_dir().join(format!("metric_videos_history_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+
+        let day1 = Session{video: String::from("vid1"), video_index: None, session: String::from("a"), ip: None, start: 1_600_000_000, end: 1_600_000_100, max_percent: 40, completed: false};
+        let day2 = Session{video: String::from("vid3"), video_index: None, session: String::from("b"), ip: Some(IpAddr::from([127, 0, 0, 1])), start: 1_600_100_000, end: 1_600_100_300, max_percent: 100, completed: true};
+        record_in(&dir, &[day1.clone(), day2.clone()]).expect("record failed");
+        record_in(&dir, &[]).expect("empty record failed");
+
+        assert_eq!(query_in(&dir, 0, i64::MAX).unwrap(), vec![day1.clone(), day2.clone()]);
+        assert_eq!(query_in(&dir, 1_600_000_000, 1_600_050_000).unwrap(), vec![day1.clone()]);
+        assert_eq!(query_in(&dir, 1_600_100_200, 1_600_100_300).unwrap(), vec![day2.clone()]);
+        assert!(query_in(&dir, 10, 0).unwrap().is_empty());
+        assert_eq!(days_in(&dir, MIN_DATE, MAX_DATE).unwrap(), vec![day_of(1_600_000_100), day_of(1_600_100_300)]);
+        assert_eq!(read_day_in(&dir, day_of(1_600_100_300), i64::MIN, i64::MAX).unwrap(), vec![day2.clone()]);
+
+        //reading in batches goes on from one day to the next, and carries on where it left off
+        record_in(&dir, std::slice::from_ref(&day2)).expect("record failed");
+        let mut reader = SessionReader::in_dir(&dir, days_in(&dir, MIN_DATE, MAX_DATE).unwrap());
+        assert_eq!(reader.read_batch(2).unwrap(), Some(vec![day1.clone(), day2.clone()]));
+        assert_eq!(reader.read_batch(2).unwrap(), Some(vec![day2]));
+        assert_eq!(reader.read_batch(2).unwrap(), None);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
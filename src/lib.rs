@@ -4,7 +4,19 @@ extern crate lazy_static;
 #[macro_use]
 extern crate serde;
 
+pub mod auth;
+pub mod events;
+pub mod export;
+pub mod heatmap;
+pub mod history;
+pub mod net;
+pub mod prometheus;
+pub mod quality;
+pub mod ratelimit;
 pub mod resources;
+pub mod retention;
 pub mod settings;
+pub mod summary;
+pub mod timeseries;
 pub mod updater;
 pub mod metrics;
@@ -1,12 +1,36 @@
 use chrono::Utc;
-use log::{/*error, */warn, /*info, debug, trace, log, Level*/};
+use log::{error, warn, info, /*debug, trace, log, Level*/};
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
 use std::net::IpAddr;
-use std::sync::Mutex;
+use std::sync::{Mutex, MutexGuard, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard};
 
-use crate::settings::SETTINGS;
+use crate::events::{self, WatcherEvent};
+use crate::heatmap::{Heatmap, HeatmapReport};
+use crate::history::{self, Session};
+use crate::prometheus::{REPORTS, Rejection};
+use crate::quality::{Quality, QualitySummary};
+use crate::retention::{Retention, RetentionCurve, Watched};
+use crate::settings::{Media, Reports, Settings, SETTINGS, Video};
 
+/// How many percentage points of progress we let slide when checking whether progress is plausible, to allow for rounding and timer jitter.
+const PROGRESS_SLACK: f32 = 2.0;
+
+/// Length assumed for videos the catalog doesn't give the length of, when checking whether progress is plausible.
+/// A player saying its video is shorter isn't believed, or it could finish any video in a moment by saying it's a second long.
+const SHORTEST_DURATION_SECS: f64 = 30.0;
+
+/// How many seconds past the end of the video a reported position may be, to allow for players rounding the duration down.
+pub const POSITION_SLACK_SECS: f64 = 1.0;
+
+/// How many pieces the watchers of each video are split into, so reports from different watchers of one video rarely wait on each other.
+const SHARDS: usize = 16;
+
+/**
+A copy of the live metrics at one moment, as served to dashboards and scrapers.
+*/
 #[derive(Serialize)]
 pub struct SiteMetrics
 {
@@ -16,56 +40,1057 @@ pub struct SiteMetrics
 #[derive(Serialize)]
 pub struct VideoMetrics
 {
-    pub watchers: HashMap<IpAddr,Watcher>
+    /// Catalog id of the video
+    pub id: String,
+    /// Length of the video in seconds, if the catalog says
+    #[serde(skip)]
+    pub duration: Option<u32>,
+    /// Live watchers of this video, keyed by session ID
+    pub watchers: HashMap<String,Watcher>,
+    /// How playback is going for the live watchers altogether
+    pub quality: QualitySummary
 }
 
-#[derive(Serialize)]
+/**
+How many watchers there are and where they've got to, counted without copying the watchers, as served to scrapers.
+*/
+#[derive(Clone, Debug, PartialEq)]
+pub struct SiteTally
+{
+    pub videos: Vec<VideoTally>,
+    /// How many different addresses all the watchers are at
+    pub distinct_ips: usize
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct VideoTally
+{
+    /// Catalog id of the video
+    pub id: String,
+    pub watchers: u64,
+    /// How many watchers are in each state, in the order of `PlayerState::ALL`
+    pub by_state: [u64; 6],
+    /// How many watchers are at each whole percent, from 0 to 100
+    pub by_percent: [u64; 101]
+}
+
+impl VideoTally
+{
+    pub fn new(id: &str) -> Self
+    {
+        VideoTally{id: id.to_owned(), watchers: 0, by_state: [0; 6], by_percent: [0; 101]}
+    }
+
+    /// Count one watcher.
+    pub fn add(&mut self, state: PlayerState, percent: u8)
+    {
+        self.watchers += 1;
+        self.by_state[state as usize] += 1;
+        self.by_percent[usize::from(percent.min(100))] += 1;
+    }
+}
+
+#[derive(Serialize, Clone)]
 pub struct Watcher
 {
+    pub ip: IpAddr,
+    /// How far into the video they are, worked out from the position where the player gives one
     pub percent: u8,
-    pub when: i64
+    /// How many seconds into the video they are. Estimated from the percent for players that only give that,
+    /// and unknown if the catalog doesn't say how long the video is either.
+    pub position: Option<f64>,
+    /// Length of the video in seconds, as their player sees it, or else as the catalog says
+    pub duration: Option<f64>,
+    pub when: i64,
+    pub start: i64,
+    /// Furthest point reached by actually playing the video, as opposed to skipping ahead
+    pub max_percent: u8,
+    /// What their player is doing, as of its last event
+    pub state: PlayerState,
+    /// How many times they've jumped to another position
+    pub seeks: u32,
+    /// Whether they played through to the end, rather than skipping there
+    pub completed: bool,
+    /// How playback is going, as of the last report that said
+    pub quality: Quality,
+    /// What they're watching with, if their player said
+    pub client: Option<ClientInfo>,
+    /// Which parts of the video they've watched, for the retention curve once their session ends
+    #[serde(skip)]
+    pub watched: Watched,
+    /// How far they've got by playing, in seconds, for telling which parts of the heatmap they've seen before
+    #[serde(skip)]
+    pub furthest: f64,
+    /// Unix time in milliseconds of the last report, for turning away reports that come too fast
+    #[serde(skip)]
+    pub last_ms: i64
+}
+
+/**
+Something that happened in a watcher's player, sent along with a report of its position.
+Reports that are just the position, including all of those from players that predate events, are `Progress`.
+*/
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum PlayerEvent
+{
+    #[default]
+    Progress,
+    Play,
+    Pause,
+    /// The watcher jumped to the position in the report
+    Seeking,
+    /// Playback reached the end
+    Ended,
+    /// Playback stalled waiting for data
+    Buffering,
+    Error
+}
+
+/**
+What a watcher's player tells us in one report.
+It has to say where the watcher is, either as a position in seconds or, for older players, in percent.
+*/
+#[derive(Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct WatcherReport
+{
+    /// How far into the video they are, for players that only say it in percent
+    #[serde(default)]
+    pub percent: Option<u8>,
+    /// How many seconds into the video they are
+    #[serde(default)]
+    pub position: Option<f64>,
+    /// Length of the video in seconds, as the player sees it. If left out, the catalog's is used.
+    #[serde(default)]
+    pub duration: Option<f64>,
+    /// What just happened in the player, if anything
+    #[serde(default)]
+    pub event: PlayerEvent,
+    /// How playback is going, from players that measure it
+    #[serde(default)]
+    pub quality: Option<Quality>,
+    /// What the watcher is watching with, from players that say
+    #[serde(default)]
+    pub client: Option<ClientInfo>
+}
+
+impl WatcherReport
+{
+    /// A report of how far the watcher is in percent and an event, without anything else.
+    pub fn new(percent: u8, event: PlayerEvent) -> Self
+    {
+        WatcherReport{percent: Some(percent), event, ..WatcherReport::default()}
+    }
+
+    /// A report of the position in seconds and an event, without anything else.
+    pub fn at(position: f64, duration: Option<f64>, event: PlayerEvent) -> Self
+    {
+        WatcherReport{position: Some(position), duration, event, ..WatcherReport::default()}
+    }
+}
+
+/**
+The player software a watcher is using, as it describes itself.
+*/
+#[derive(Deserialize, Serialize, Clone, Debug, Default, PartialEq)]
+#[serde(default)]
+pub struct ClientInfo
+{
+    /// Name of the player, like "metric_videos web"
+    pub player: String,
+    pub version: String
 }
 
-impl SiteMetrics
+impl ClientInfo
 {
-    pub fn new() -> Self
+    /// Longest name or version we keep, so a misbehaving player can't fill memory with them.
+    pub const MAX_LEN: usize = 64;
+
+    /**
+    Check for a description no real player would send.
+
+    # Errors
+    A description of the problem: a name or version that's too long or has control characters in it.
+    */
+    pub fn validate(&self) -> Result<(), String>
     {
-        let mut out = SiteMetrics{videos: Vec::new()};
-        for _index in 0 .. SETTINGS.media.videos.len()
+        for (what, value) in [("player", &self.player), ("version", &self.version)].iter()
         {
-            out.videos.push(VideoMetrics{watchers: HashMap::new()});
+            if value.len() > ClientInfo::MAX_LEN || value.chars().any(char::is_control)
+            {
+                return Err(format!("malformed client {}", what));
+            }
         }
-        out
+        Ok(())
+    }
+}
+
+/**
+What a watcher's player is doing, as of the last event it sent.
+*/
+#[derive(Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum PlayerState
+{
+    Playing,
+    Paused,
+    Seeking,
+    Buffering,
+    Ended,
+    Error
+}
+
+impl PlayerState
+{
+    pub const ALL: [PlayerState; 6] = [PlayerState::Playing, PlayerState::Paused, PlayerState::Seeking, PlayerState::Buffering, PlayerState::Ended, PlayerState::Error];
+
+    pub fn label(self) -> &'static str
+    {
+        match self
+        {
+            PlayerState::Playing => "playing",
+            PlayerState::Paused => "paused",
+            PlayerState::Seeking => "seeking",
+            PlayerState::Buffering => "buffering",
+            PlayerState::Ended => "ended",
+            PlayerState::Error => "error"
+        }
+    }
+
+    /// The state a player goes into on an event. Plain progress reports leave it as it was.
+    fn after(self, event: PlayerEvent) -> PlayerState
+    {
+        match event
+        {
+            PlayerEvent::Progress => self,
+            PlayerEvent::Play => PlayerState::Playing,
+            PlayerEvent::Pause => PlayerState::Paused,
+            PlayerEvent::Seeking => PlayerState::Seeking,
+            PlayerEvent::Ended => PlayerState::Ended,
+            PlayerEvent::Buffering => PlayerState::Buffering,
+            PlayerEvent::Error => PlayerState::Error
+        }
+    }
+}
+
+/**
+The live metrics, shared by everything that reports, reads or prunes watchers.
+
+Each video's watchers are split by session across several locks, so a report only ever waits on the few others
+that land in the same piece, and never gets dropped for want of the lock. The list of videos has a lock of its own,
+which is only taken for writing when the catalog changes.
+
+Lock order, to stay clear of deadlocks: the list of videos, then shards in order, then the heatmap of the same piece as a shard, then a video's retention.
+Events for dashboards are handed off without taking any lock, so sending them never holds up reports,
+and snapshots take one shard at a time, so making one never holds up more than a shard's worth of reports.
+*/
+pub struct MetricsStore
+{
+    videos: RwLock<Vec<VideoShards>>
+}
+
+struct VideoShards
+{
+    id: String,
+    duration: Option<u32>,
+    /// Seconds its watchers may go quiet for, if the catalog sets it for this video
+    timeout: Option<u32>,
+    shards: Vec<Mutex<HashMap<String, Watcher>>>,
+    /// Which parts of the video get watched and skipped, split the same way as the watchers, so each report only touches its own piece
+    heatmaps: Vec<Mutex<Heatmap>>,
+    /// Where the watchers whose sessions have ended stopped watching
+    retention: Mutex<Retention>
+}
+
+impl VideoShards
+{
+    fn new(video: &Video) -> Self
+    {
+        VideoShards{id: video.id.clone(), duration: video.duration, timeout: video.watcher_timeout_secs, shards: (0..SHARDS).map(|_| Mutex::new(HashMap::new())).collect(),
+            heatmaps: (0..SHARDS).map(|_| Mutex::new(Heatmap::default())).collect(), retention: Mutex::new(Retention::default())}
+    }
+
+    /// End a watcher's session, counting it toward the retention curve.
+    fn end(&self, session: &str, w: Watcher) -> Session
+    {
+        lock(&self.retention).add(&w.watched, w.duration);
+        end_session(&self.id, session, w)
+    }
+
+    /// Lock the piece of this video's watchers that a session belongs in.
+    fn shard(&self, session: &str) -> MutexGuard<'_, HashMap<String, Watcher>>
+    {
+        lock(&self.shards[shard_index(session)])
+    }
+}
+
+impl MetricsStore
+{
+    /**
+    Make a store with no watchers, for the videos in a catalog.
+    */
+    pub fn new(media: &Media) -> Self
+    {
+        MetricsStore{videos: RwLock::new(media.videos.iter().map(VideoShards::new).collect())}
     }
 
-    pub fn report(&mut self, ip: IpAddr, video_index: usize, percent: u8)
+    /**
+    Take a copy of the metrics as they are now.
+    */
+    pub fn snapshot(&self) -> SiteMetrics
     {
-        if video_index >= self.videos.len()
+        self.snapshot_since().1
+    }
+
+    /**
+    Take a copy of the metrics, along with where it stands among the events sent to dashboards.
+    Each piece of each video is copied under its own lock in turn, so a report only ever waits on the copying of its own piece.
+    That means the copy isn't all from quite the same moment, which replaying the events from the sequence number on top of it makes up for.
+
+    # Returns
+    The sequence number of the first event that may not be reflected in the copy, and the copy.
+
+    # Examples
+    ```
+    use metric_videos::events;
+    use metric_videos::metrics::{MetricsStore, PlayerEvent, WatcherReport};
+    use metric_videos::settings::{Media, Reports, Video};
+    let store = MetricsStore::new(&Media{videos: vec![Video::from_url("http://example.com/intro.mp4")]});
+    let (since, snapshot) = store.snapshot_since();
+    assert!(snapshot.videos[0].watchers.is_empty());
+
+    store.report("s1", "192.0.2.1".parse().unwrap(), "intro", &WatcherReport::new(10, PlayerEvent::Progress), &Reports::default()).unwrap();
+    assert!(events::sequence() > since);
+    ```
+    */
+    pub fn snapshot_since(&self) -> (u64, SiteMetrics)
+    {
+        let videos = read(&self.videos);
+        //read before copying anything, so every event numbered before it is already reflected in what gets copied
+        let since = events::sequence();
+        let snapshot = SiteMetrics{videos: videos.iter().map(|v| {
+            let mut watchers = HashMap::new();
+            for shard in &v.shards
+            {
+                watchers.extend(lock(shard).iter().map(|(k, w)| (k.clone(), w.clone())));
+            }
+            let quality = QualitySummary::of(watchers.values().map(|w| &w.quality));
+            VideoMetrics{id: v.id.clone(), duration: v.duration, watchers, quality}
+        }).collect()};
+        (since, snapshot)
+    }
+
+    /**
+    Record a progress report from a watcher, adding them if they're new.
+
+    Reports are checked over first. Those that can't be right are turned away, while those that jump further ahead
+    than could have been watched in the time since the last one are taken as the watcher skipping ahead:
+    their position is shown, but it doesn't count toward how far they got. Either way, it's counted and logged,
+    unless the player said it was seeking, in which case it's just counted as one of the watcher's seeks.
+    The minimum interval between reports only applies to plain progress reports, so no event from the player is lost.
+
+    # Parameters
+    - `session`: ID identifying the watcher, unique per player rather than per address
+    - `ip`: address the report came from, kept as an attribute of the watcher
+    - `video_id`: catalog id of the video they are watching
+    - `report`: what their player says
+    - `rules`: limits on what reports to believe
+
+    # Errors
+    The reason the report was turned away, in which case nothing was recorded.
+    */
+    pub fn report(&self, session: &str, ip: IpAddr, video_id: &str, report: &WatcherReport, rules: &Reports) -> Result<(), Rejection>
+    {
+        let event = report.event;
+        let videos = read(&self.videos);
+        let video = match videos.iter().find(|v| v.id == video_id)
+        {
+            Some(v) => v,
+            None => return Err(rejected(Rejection::InvalidVideo, session, format!("invalid video id {}", video_id)))
+        };
+        let duration = report.duration.or_else(|| video.duration.map(f64::from)).filter(|d| *d > 0.0);
+        let (percent, position) = match (report.position, report.percent, duration)
+        {
+            (Some(p), _, Some(d)) => {
+                if p < 0.0 || p > d + POSITION_SLACK_SECS
+                {
+                    return Err(rejected(Rejection::OutOfRange, session, format!("position {}s of {}s", p, d)));
+                }
+                (percent_of(p, d), Some(p))
+            },
+            (Some(_), _, None) => return Err(rejected(Rejection::Malformed, session, String::from("position without a duration"))),
+            (None, Some(pct), _) => {
+                if pct > 100
+                {
+                    return Err(rejected(Rejection::OutOfRange, session, format!("{} percent", pct)));
+                }
+                (pct, duration.map(|d| d * f64::from(pct) / 100.0))
+            },
+            (None, None, _) => return Err(rejected(Rejection::Malformed, session, String::from("neither a position nor a percent")))
+        };
+        if let Some(Err(e)) = report.quality.as_ref().map(Quality::validate)
+        {
+            return Err(rejected(Rejection::Malformed, session, e));
+        }
+        if let Some(Err(e)) = report.client.as_ref().map(ClientInfo::validate)
         {
-            warn!("Invalid video index: {}", video_index);
-            return;
+            return Err(rejected(Rejection::Malformed, session, e));
         }
 
-        let now = Utc::now().timestamp();
+        let now_ms = Utc::now().timestamp_millis();
+        let now = now_ms / 1000;
 
-        match self.videos[video_index].watchers.get_mut(&ip)
+        //the catalog's length is trusted over the player's, which is only believed so far
+        let check_duration = video.duration.map(f64::from).filter(|d| *d > 0.0).unwrap_or_else(|| duration.unwrap_or(0.0).max(SHORTEST_DURATION_SECS));
+        let seek = event == PlayerEvent::Seeking;
+        let shard = shard_index(session);
+        let mut watchers = lock(&video.shards[shard]);
+        let change = match watchers.get_mut(session)
         {
             Some(w) => {
+                if event == PlayerEvent::Progress && now_ms - w.last_ms < i64::from(rules.min_interval_ms)
+                {
+                    return Err(rejected(Rejection::TooFrequent, session, format!("{} ms after the last one", now_ms - w.last_ms)));
+                }
+                let plausible = plausible_progress(w.percent, percent, now - w.when, check_duration, rules.max_speed);
+                //only progress made by playing on from the furthest point so far counts, not by skipping ahead
+                if plausible && f32::from(w.percent) <= f32::from(w.max_percent) + PROGRESS_SLACK
+                {
+                    w.max_percent = w.max_percent.max(percent);
+                }
+                //unlike how far they got, rewatching counts toward retention, but skipping over a part doesn't
+                if plausible && !seek
+                {
+                    w.watched.mark_range(w.percent, percent);
+                }else{
+                    w.watched.mark(percent);
+                }
+                if let (Some(from), Some(to)) = (w.position, position)
+                {
+                    //reports are timestamped to the millisecond, but players round their positions, so allow for a second more
+                    let max_played = ((now_ms - w.last_ms).max(0) as f64 / 1000.0 + 1.0) * f64::from(rules.max_speed);
+                    lock(&video.heatmaps[shard]).follow(from, to, max_played, seek, &mut w.furthest);
+                }
+                w.ip = ip;
                 w.percent = percent;
+                w.position = position;
+                w.duration = duration;
                 w.when = now;
+                w.last_ms = now_ms;
+                w.state = w.state.after(event);
+                w.seeks += u32::from(seek);
+                w.completed |= reached_end(w.max_percent, event);
+                if let Some(quality) = &report.quality
+                {
+                    w.quality = quality.clone();
+                }
+                if report.client.is_some()
+                {
+                    w.client = report.client.clone();
+                }
+                if !plausible && !seek
+                {
+                    implausible(session, format!("jump to {} percent", percent));
+                }
+                WatcherEvent::Update{video: video.id.clone(), session: session.to_owned(), ip, percent, position, duration, state: w.state}
             },
-            None => {self.videos[video_index].watchers.insert(ip, Watcher{percent, when: now});}
+            None => {
+                let plausible = plausible_progress(0, percent, 0, check_duration, rules.max_speed);
+                let max_percent = if plausible {percent} else {0};
+                let state = PlayerState::Playing.after(event);
+                let mut watched = Watched::default();
+                watched.mark(percent);
+                watchers.insert(session.to_owned(), Watcher{
+                    ip, percent, position, duration, when: now, start: now, max_percent, state, seeks: u32::from(seek), completed: reached_end(max_percent, event),
+                    quality: report.quality.clone().unwrap_or_default(), client: report.client.clone(), watched, furthest: position.unwrap_or(0.0), last_ms: now_ms
+                });
+                if !plausible && !seek
+                {
+                    implausible(session, format!("start at {} percent", percent));
+                }
+                WatcherEvent::Join{video: video.id.clone(), session: session.to_owned(), ip, percent, position, duration, state}
+            }
+        };
+        // Published while the shard is still locked, so dashboards get each watcher's events in the order they happened
+        events::send(change);
+        Ok(())
+    }
+
+    /**
+    Remove every watcher that hasn't reported recently, ending their viewing session.
+
+    # Parameters
+    - `now`: current unix timestamp
+    - `max_age`: how many seconds a watcher may go without reporting before it's considered gone, for videos that don't set their own
+
+    # Returns
+    The sessions of the watchers that were removed, ready to be recorded in the history.
+    */
+    pub fn prune(&self, now: i64, max_age: i64) -> Vec<Session>
+    {
+        let videos = read(&self.videos);
+        let mut ended: Vec<Session> = Vec::new();
+        for video in videos.iter()
+        {
+            let max_age = video.timeout.map_or(max_age, i64::from);
+            for shard in &video.shards
+            {
+                let mut watchers = lock(shard);
+                let stale: Vec<String> = watchers.iter().filter(|(_, w)| now - w.when > max_age).map(|(s, _)| s.clone()).collect();
+                for session in stale
+                {
+                    if let Some(w) = watchers.remove(&session)
+                    {
+                        ended.push(video.end(&session, w));
+                    }
+                }
+            }
+        }
+        ended
+    }
+
+    /**
+    Remove every watcher, ending all the viewing sessions still going, for when the app is shutting down.
+
+    # Returns
+    The sessions of all the watchers, ready to be recorded in the history.
+    */
+    pub fn drain(&self) -> Vec<Session>
+    {
+        let videos = read(&self.videos);
+        let mut ended: Vec<Session> = Vec::new();
+        for video in videos.iter()
+        {
+            for shard in &video.shards
+            {
+                for (session, w) in lock(shard).drain()
+                {
+                    ended.push(video.end(&session, w));
+                }
+            }
+        }
+        ended
+    }
+
+    /**
+    Remove one watcher right away, for when we know they've left rather than waiting for them to go stale.
+
+    # Parameters
+    - `session`: session ID of the watcher
+    - `video_id`: catalog id of the video they were watching
+
+    # Returns
+    The watcher's finished session, ready to be recorded in the history, or None if there was no such watcher.
+    */
+    pub fn depart(&self, session: &str, video_id: &str) -> Option<Session>
+    {
+        let videos = read(&self.videos);
+        let video = videos.iter().find(|v| v.id == video_id)?;
+        let mut watchers = video.shard(session);
+        let w = watchers.remove(session)?;
+        Some(video.end(session, w))
+    }
+
+    /**
+    Count the watchers of each video, without copying anything else.
+
+    # Returns
+    The catalog id and number of watchers of every video, in catalog order.
+    */
+    pub fn counts(&self) -> Vec<(String, u32)>
+    {
+        let videos = read(&self.videos);
+        videos.iter().map(|v| (v.id.clone(), v.shards.iter().map(|s| lock(s).len() as u32).sum())).collect()
+    }
+
+    /**
+    Count the watchers of each video by what their player is doing and how far in they are, without copying the watchers.
+    Each shard is only locked for as long as it takes to count its own watchers.
+    */
+    pub fn tally(&self) -> SiteTally
+    {
+        let videos = read(&self.videos);
+        let mut ips = HashSet::new();
+        let tallies = videos.iter().map(|v| {
+            let mut tally = VideoTally::new(&v.id);
+            for shard in &v.shards
+            {
+                for w in lock(shard).values()
+                {
+                    tally.add(w.state, w.percent);
+                    ips.insert(w.ip);
+                }
+            }
+            tally
+        }).collect();
+        SiteTally{videos: tallies, distinct_ips: ips.len()}
+    }
+
+    /**
+    Work out where the watchers of a video stopped watching, from all the sessions that have ended since the app started.
+    Watchers who are still watching aren't counted until they're done.
+
+    # Parameters
+    - `video_id`: catalog id of the video
+
+    # Returns
+    The retention curve, or None if there's no such video.
+    */
+    pub fn retention(&self, video_id: &str) -> Option<RetentionCurve>
+    {
+        let videos = read(&self.videos);
+        let video = videos.iter().find(|v| v.id == video_id)?;
+        let curve = lock(&video.retention).curve(&video.id, video.duration);
+        Some(curve)
+    }
+
+    /**
+    Work out which parts of a video get watched, watched again and skipped, from all the reports since the app started,
+    including those of watchers who are still watching. Only watchers whose position is known count,
+    which needs the player or the catalog to say how long the video is.
+
+    # Parameters
+    - `video_id`: catalog id of the video
+
+    # Returns
+    The heatmap, or None if there's no such video.
+    */
+    pub fn heatmap(&self, video_id: &str) -> Option<HeatmapReport>
+    {
+        let videos = read(&self.videos);
+        let video = videos.iter().find(|v| v.id == video_id)?;
+        let mut total = Heatmap::default();
+        for heatmap in &video.heatmaps
+        {
+            total.add(&lock(heatmap));
         }
+        Some(total.report(&video.id, video.duration))
+    }
+
+    /**
+    Bring the list of videos in line with a new catalog, matching videos up by id.
+    Videos that are still in the catalog keep their watchers, new ones start out empty,
+    and the watchers of videos that were removed are ended.
+
+    # Parameters
+    - `media`: the new catalog
+
+    # Returns
+    The sessions of the watchers of removed videos, ready to be recorded in the history.
+    */
+    pub fn reconcile(&self, media: &Media) -> Vec<Session>
+    {
+        reconcile_videos(&mut write(&self.videos), media)
+    }
+}
+
+/// Which piece of a video's watchers a session belongs in.
+fn shard_index(session: &str) -> usize
+{
+    let mut hasher = DefaultHasher::new();
+    session.hash(&mut hasher);
+    hasher.finish() as usize % SHARDS
+}
+
+fn reconcile_videos(videos: &mut Vec<VideoShards>, media: &Media) -> Vec<Session>
+{
+    let mut old: HashMap<String, VideoShards> = videos.drain(..).map(|v| (v.id.clone(), v)).collect();
+    for video in &media.videos
+    {
+        let mut shards = old.remove(&video.id).unwrap_or_else(|| VideoShards::new(video));
+        shards.duration = video.duration;
+        shards.timeout = video.watcher_timeout_secs;
+        videos.push(shards);
+    }
+
+    let mut ended: Vec<Session> = Vec::new();
+    for (video_id, video) in old
+    {
+        for shard in video.shards
+        {
+            for (session, w) in shard.into_inner().unwrap_or_else(PoisonError::into_inner)
+            {
+                ended.push(end_session(&video_id, &session, w));
+            }
+        }
+    }
+    ended
+}
+
+/**
+Decide whether moving from one position to another could have been done by playing the video, rather than skipping ahead.
+Going backwards always could.
+
+# Parameters
+- `from`, `to`: the earlier and later positions, in percent
+- `elapsed`: seconds between the two
+- `duration`: length of the video in seconds, more than zero
+- `max_speed`: fastest playback speed to allow for
+*/
+fn plausible_progress(from: u8, to: u8, elapsed: i64, duration: f64, max_speed: f32) -> bool
+{
+    if to <= from
+    {
+        return true;
+    }
+    //reports are timestamped to the second, so allow for up to a second more than it looks like
+    let allowed = (elapsed.max(0) + 1) as f64 * f64::from(max_speed) * 100.0 / duration + f64::from(PROGRESS_SLACK);
+    f64::from(to - from) <= allowed
+}
+
+/**
+How far into a video a position is, in whole percent, never more than 100.
+
+# Examples
+```
+use metric_videos::metrics::percent_of;
+assert_eq!(percent_of(30.5, 61.0), 50);
+assert_eq!(percent_of(7199.0, 7200.0), 99);
+assert_eq!(percent_of(7201.0, 7200.0), 100);
+```
+*/
+pub fn percent_of(position: f64, duration: f64) -> u8
+{
+    (position / duration * 100.0).floor().clamp(0.0, 100.0) as u8
+}
+
+/// Whether a watcher has played through to the end, as of an event from their player and how far they've got by playing.
+fn reached_end(max_percent: u8, event: PlayerEvent) -> bool
+{
+    event == PlayerEvent::Ended && f32::from(max_percent) + PROGRESS_SLACK >= 100.0
+}
+
+/// Count and log a report that's being turned away, handing back the reason.
+fn rejected(reason: Rejection, session: &str, detail: String) -> Rejection
+{
+    REPORTS.rejected(reason);
+    warn!("Rejected report from watcher {}: {}", session, detail);
+    reason
+}
+
+/// Count and log a report whose progress isn't being counted, though the report itself is accepted.
+fn implausible(session: &str, detail: String)
+{
+    REPORTS.implausible();
+    info!("Not counting progress from watcher {}: {}", session, detail);
+}
+
+/// Announce that a watcher who has been removed from the metrics left, and turn them into a session for the history.
+fn end_session(video_id: &str, session: &str, w: Watcher) -> Session
+{
+    events::send(WatcherEvent::Leave{video: video_id.to_owned(), session: session.to_owned()});
+    Session{video: video_id.to_owned(), video_index: None, session: session.to_owned(), ip: Some(w.ip), start: w.start, end: w.when, max_percent: w.max_percent, completed: w.completed}
+}
+
+/*
+A panic while holding one of these locks leaves nothing half-done that later users would trip over,
+so carry on with the data rather than giving up on it for the rest of the run.
+*/
+fn lock<T>(m: &Mutex<T>) -> MutexGuard<'_, T>
+{
+    m.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+fn read<T>(l: &RwLock<T>) -> RwLockReadGuard<'_, T>
+{
+    l.read().unwrap_or_else(PoisonError::into_inner)
+}
+
+fn write<T>(l: &RwLock<T>) -> RwLockWriteGuard<'_, T>
+{
+    l.write().unwrap_or_else(PoisonError::into_inner)
+}
+
+/**
+Put new settings into effect, bringing the live metrics in line with the new video catalog.
+
+Watchers of videos that are no longer in the catalog are ended and recorded in the history,
+and connected dashboards are sent a fresh snapshot.
+
+# Parameters
+- `settings`: the new settings, which should already have been validated
+*/
+pub fn apply_settings(settings: Settings)
+{
+    // Swap while holding the list of videos for writing, so no report can see the new catalog with the old metrics or vice versa
+    let ended = {
+        let mut videos = write(&METRICS.videos);
+        SETTINGS.replace(settings);
+        reconcile_videos(&mut videos, &SETTINGS.get().media)
+    };
+    resync_dashboards();
+
+    if let Err(e) = history::record(&ended)
+    {
+        error!("Couldn't record {} session(s) to history: {}", ended.len(), e);
     }
 }
 
-impl Default for SiteMetrics {
-    fn default() -> Self {
-        SiteMetrics::new()
+/**
+Send every connected dashboard a fresh snapshot, for when the events they were sent no longer add up to the live metrics.
+*/
+pub fn resync_dashboards()
+{
+    // Made into JSON once the locks are let go, so no report waits on it; the events since it was begun are sent on after it
+    let (since, snapshot) = METRICS.snapshot_since();
+    if let Ok(json) = serde_json::to_string(&snapshot)
+    {
+        events::resync(&json, since);
     }
 }
 
 lazy_static!
 {
-    pub static ref METRICS: Mutex<SiteMetrics> = Mutex::new(SiteMetrics::new());
-}
\ No newline at end of file
+    pub static ref METRICS: MetricsStore = MetricsStore::new(&SETTINGS.get().media);
+}
+
+/*
+Test the bookkeeping that doesn't depend on the configured catalog
+*/
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    // metrics::MetricsStore::reconcile()
+    #[test]
+    fn reconcile_by_id()
+    {
+        let metrics = MetricsStore::new(&Media{videos: vec![Video::from_url("http://example.com/a.mp4"), Video::from_url("http://example.com/b.mp4")]});
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+        metrics.report("s1", ip, "a", &WatcherReport::new(10, PlayerEvent::Progress), &Reports::default()).unwrap();
+        metrics.report("s2", ip, "b", &WatcherReport::new(5, PlayerEvent::Progress), &Reports::default()).unwrap();
+
+        let media = Media{videos: vec![Video::from_url("http://example.com/c.mp4"), Video::from_url("http://example.com/a.mp4")]};
+        let ended = metrics.reconcile(&media);
+
+        let snapshot = metrics.snapshot();
+        let ids: Vec<&str> = snapshot.videos.iter().map(|v| v.id.as_str()).collect();
+        assert_eq!(ids, vec!["c", "a"]);
+        assert_eq!(snapshot.videos[1].watchers["s1"].percent, 10);
+        assert!(snapshot.videos[0].watchers.is_empty());
+        assert_eq!(ended.len(), 1);
+        assert_eq!((ended[0].video.as_str(), ended[0].session.as_str(), ended[0].max_percent), ("b", "s2", 5));
+    }
+
+    // metrics::MetricsStore::report()
+    #[test]
+    fn report_sanity_checks()
+    {
+        let mut video = Video::from_url("http://example.com/a.mp4");
+        video.duration = Some(1000);
+        let metrics = MetricsStore::new(&Media{videos: vec![video]});
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+        let rules = Reports{require_token: true, max_speed: 2.0, min_interval_ms: 0};
+        let watcher = |session: &str| metrics.snapshot().videos[0].watchers[session].clone();
+
+        assert_eq!(metrics.report("s1", ip, "nope", &WatcherReport::new(10, PlayerEvent::Progress), &rules), Err(Rejection::InvalidVideo));
+        assert_eq!(metrics.report("s1", ip, "a", &WatcherReport::new(101, PlayerEvent::Progress), &rules), Err(Rejection::OutOfRange));
+
+        //starting near the beginning and playing on counts
+        metrics.report("s1", ip, "a", &WatcherReport::new(1, PlayerEvent::Progress), &rules).unwrap();
+        metrics.report("s1", ip, "a", &WatcherReport::new(2, PlayerEvent::Progress), &rules).unwrap();
+        assert_eq!(watcher("s1").max_percent, 2);
+
+        //skipping to the end shows where they are, but doesn't count as having got there, even playing on from there
+        metrics.report("s1", ip, "a", &WatcherReport::new(99, PlayerEvent::Progress), &rules).unwrap();
+        metrics.report("s1", ip, "a", &WatcherReport::new(100, PlayerEvent::Progress), &rules).unwrap();
+        assert_eq!(watcher("s1").percent, 100);
+        assert_eq!(watcher("s1").max_percent, 2);
+
+        //starting at the end doesn't count either
+        metrics.report("s2", ip, "a", &WatcherReport::new(100, PlayerEvent::Progress), &rules).unwrap();
+        assert_eq!(watcher("s2").max_percent, 0);
+
+        let strict = Reports{min_interval_ms: 60_000, ..rules};
+        assert_eq!(metrics.report("s1", ip, "a", &WatcherReport::new(50, PlayerEvent::Progress), &strict), Err(Rejection::TooFrequent));
+        assert_eq!(watcher("s1").percent, 100);
+
+        //leaving and going quiet both end sessions
+        assert_eq!(metrics.depart("s1", "a").map(|s| s.max_percent), Some(2));
+        assert!(metrics.depart("s1", "a").is_none());
+        assert_eq!(metrics.prune(Utc::now().timestamp() + 60, 5).len(), 1);
+        assert!(metrics.snapshot().videos[0].watchers.is_empty());
+    }
+
+    // metrics::MetricsStore::report() with events from the player
+    #[test]
+    fn report_player_events()
+    {
+        let mut video = Video::from_url("http://example.com/a.mp4");
+        video.duration = Some(1000);
+        let metrics = MetricsStore::new(&Media{videos: vec![video, Video::from_url("http://example.com/b.mp4")]});
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+        //events get through however soon they come, unlike plain progress
+        let rules = Reports{min_interval_ms: 60_000, ..Reports::default()};
+        let watcher = |session: &str| metrics.snapshot().videos[0].watchers[session].clone();
+
+        metrics.report("s1", ip, "a", &WatcherReport::new(0, PlayerEvent::Progress), &rules).unwrap();
+        assert_eq!(watcher("s1").state, PlayerState::Playing);
+        metrics.report("s1", ip, "a", &WatcherReport::new(1, PlayerEvent::Pause), &rules).unwrap();
+        assert_eq!(watcher("s1").state, PlayerState::Paused);
+        assert_eq!(metrics.report("s1", ip, "a", &WatcherReport::new(1, PlayerEvent::Progress), &rules), Err(Rejection::TooFrequent));
+        metrics.report("s1", ip, "a", &WatcherReport::new(1, PlayerEvent::Play), &rules).unwrap();
+        assert_eq!((watcher("s1").state, watcher("s1").max_percent), (PlayerState::Playing, 1));
+
+        //seeking to the end is a seek, and ending up there doesn't count as finishing
+        metrics.report("s1", ip, "a", &WatcherReport::new(99, PlayerEvent::Seeking), &rules).unwrap();
+        metrics.report("s1", ip, "a", &WatcherReport::new(100, PlayerEvent::Ended), &rules).unwrap();
+        let w = watcher("s1");
+        assert_eq!((w.state, w.seeks, w.max_percent, w.completed), (PlayerState::Ended, 1, 1, false));
+
+        //without knowing the duration, jumping to the end doesn't count as finishing either
+        metrics.report("s2", ip, "b", &WatcherReport::new(0, PlayerEvent::Play), &rules).unwrap();
+        metrics.report("s2", ip, "b", &WatcherReport::new(100, PlayerEvent::Ended), &rules).unwrap();
+        assert_eq!(metrics.depart("s2", "b").map(|s| s.completed), Some(false));
+
+        //but playing through does, going no faster than the shortest video could
+        metrics.report("s3", ip, "b", &WatcherReport::new(0, PlayerEvent::Play), &rules).unwrap();
+        for percent in (8..=96).step_by(8)
+        {
+            metrics.report("s3", ip, "b", &WatcherReport::new(percent, PlayerEvent::Buffering), &rules).unwrap();
+        }
+        metrics.report("s3", ip, "b", &WatcherReport::new(100, PlayerEvent::Ended), &rules).unwrap();
+        assert_eq!(metrics.depart("s3", "b").map(|s| s.completed), Some(true));
+    }
+
+    // metrics::MetricsStore::report() with how playback is going
+    #[test]
+    fn report_quality()
+    {
+        let metrics = MetricsStore::new(&Media{videos: vec![Video::from_url("http://example.com/a.mp4")]});
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+        let rules = Reports{min_interval_ms: 0, ..Reports::default()};
+        let with_quality = |percent: u8, quality: Quality| WatcherReport{percent: Some(percent), quality: Some(quality), ..WatcherReport::default()};
+
+        metrics.report("s1", ip, "a", &with_quality(1, Quality{startup_ms: Some(300), ..Quality::default()}), &rules).unwrap();
+        metrics.report("s2", ip, "a", &with_quality(1, Quality{startup_ms: Some(900), rebuffers: 2, rebuffer_ms: 1500, ..Quality::default()}), &rules).unwrap();
+        //reports without anything to say about quality leave it as it was
+        metrics.report("s2", ip, "a", &WatcherReport::new(2, PlayerEvent::Progress), &rules).unwrap();
+        let bad = Quality{error: Some(String::from("<script>")), ..Quality::default()};
+        assert_eq!(metrics.report("s1", ip, "a", &with_quality(2, bad), &rules), Err(Rejection::Malformed));
+
+        let snapshot = metrics.snapshot();
+        assert_eq!(snapshot.videos[0].watchers["s2"].quality.rebuffers, 2);
+        let summary = &snapshot.videos[0].quality;
+        assert_eq!((summary.startup_ms, summary.rebuffers, summary.rebuffer_ms), (Some(600), 2, 1500));
+        assert!(summary.errors.is_empty());
+    }
+
+    // metrics::MetricsStore::report() with positions in seconds
+    #[test]
+    fn report_positions()
+    {
+        let mut long = Video::from_url("http://example.com/long.mp4");
+        long.duration = Some(7200);
+        let metrics = MetricsStore::new(&Media{videos: vec![long, Video::from_url("http://example.com/unknown.mp4")]});
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+        let rules = Reports{min_interval_ms: 0, ..Reports::default()};
+        let watcher = |video: usize, session: &str| metrics.snapshot().videos[video].watchers[session].clone();
+
+        //the catalog's duration is used when the player doesn't give one
+        metrics.report("s1", ip, "long", &WatcherReport::at(71.5, None, PlayerEvent::Progress), &rules).unwrap();
+        let w = watcher(0, "s1");
+        assert_eq!((w.percent, w.position, w.duration), (0, Some(71.5), Some(7200.0)));
+        metrics.report("s1", ip, "long", &WatcherReport::at(3600.0, None, PlayerEvent::Seeking), &rules).unwrap();
+        assert_eq!(watcher(0, "s1").percent, 50);
+
+        //players that only give a percent get an estimated position
+        metrics.report("s2", ip, "long", &WatcherReport::new(25, PlayerEvent::Progress), &rules).unwrap();
+        assert_eq!(watcher(0, "s2").position, Some(1800.0));
+
+        //a position needs a duration from somewhere, and has to be within it
+        let no_duration = WatcherReport::at(10.0, None, PlayerEvent::Progress);
+        assert_eq!(metrics.report("s3", ip, "unknown", &no_duration, &rules), Err(Rejection::Malformed));
+        metrics.report("s3", ip, "unknown", &WatcherReport::at(10.0, Some(40.0), PlayerEvent::Progress), &rules).unwrap();
+        assert_eq!(watcher(1, "s3").percent, 25);
+        let past_end = WatcherReport::at(42.0, Some(40.0), PlayerEvent::Progress);
+        assert_eq!(metrics.report("s3", ip, "unknown", &past_end, &rules), Err(Rejection::OutOfRange));
+        assert_eq!(metrics.report("s3", ip, "unknown", &WatcherReport::default(), &rules), Err(Rejection::Malformed));
+    }
+
+    // metrics::MetricsStore::retention()
+    #[test]
+    fn retention_from_ended_sessions()
+    {
+        let metrics = MetricsStore::new(&Media{videos: vec![Video::from_url("http://example.com/a.mp4")]});
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+        let rules = Reports{min_interval_ms: 0, ..Reports::default()};
+        for percent in [0, 5, 10, 15, 20].iter()
+        {
+            metrics.report("s1", ip, "a", &WatcherReport::new(*percent, PlayerEvent::Progress), &rules).unwrap();
+        }
+        //skipping ahead leaves out the part skipped over
+        metrics.report("s2", ip, "a", &WatcherReport::new(0, PlayerEvent::Progress), &rules).unwrap();
+        metrics.report("s2", ip, "a", &WatcherReport::new(50, PlayerEvent::Seeking), &rules).unwrap();
+        metrics.report("s2", ip, "a", &WatcherReport::new(55, PlayerEvent::Progress), &rules).unwrap();
+        metrics.report("s2", ip, "a", &WatcherReport::new(60, PlayerEvent::Progress), &rules).unwrap();
+
+        //nobody counts until their session ends
+        assert_eq!(metrics.retention("a").map(|c| c.sessions), Some(0));
+        metrics.depart("s1", "a").unwrap();
+        metrics.prune(Utc::now().timestamp() + 60, 5);
+
+        let curve = metrics.retention("a").unwrap();
+        let watching: Vec<u64> = [0, 10, 20, 30, 50, 55, 61].iter().map(|p| curve.points[*p].watching).collect();
+        assert_eq!((curve.sessions, watching), (2, vec![2, 2, 2, 1, 1, 1, 0]));
+        let played: Vec<u64> = [0, 10, 20, 30, 50, 55, 61].iter().map(|p| curve.points[*p].played).collect();
+        assert_eq!(played, vec![2, 1, 1, 0, 1, 1, 0]);
+        assert_eq!(curve.points[0].share, 1.0);
+        assert!(metrics.retention("nope").is_none());
+    }
+
+    // metrics::MetricsStore::heatmap()
+    #[test]
+    fn heatmap_from_positions()
+    {
+        let mut video = Video::from_url("http://example.com/a.mp4");
+        video.duration = Some(10);
+        let metrics = MetricsStore::new(&Media{videos: vec![video, Video::from_url("http://example.com/b.mp4")]});
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+        let rules = Reports{min_interval_ms: 0, ..Reports::default()};
+        let at = |position: f64, event: PlayerEvent| WatcherReport::at(position, None, event);
+
+        for (position, event) in [(0.0, PlayerEvent::Play), (1.0, PlayerEvent::Progress), (2.0, PlayerEvent::Progress), (8.0, PlayerEvent::Seeking),
+            (1.0, PlayerEvent::Seeking), (2.0, PlayerEvent::Progress)].iter()
+        {
+            metrics.report("s1", ip, "a", &at(*position, *event), &rules).unwrap();
+        }
+        //jumping further than could have been played is a skip, even without the player saying
+        metrics.report("s2", ip, "a", &at(0.0, PlayerEvent::Play), &rules).unwrap();
+        metrics.report("s2", ip, "a", &at(9.0, PlayerEvent::Progress), &rules).unwrap();
+        //without a duration from anywhere, there's nothing to place percents on
+        metrics.report("s3", ip, "b", &WatcherReport::new(10, PlayerEvent::Progress), &rules).unwrap();
+
+        let heatmap = metrics.heatmap("a").unwrap();
+        assert_eq!(heatmap.segments.len(), 10);
+        let counts: Vec<(u64, u64, u64)> = heatmap.segments.iter().take(4).map(|s| (s.views, s.replays, s.skips)).collect();
+        assert_eq!(counts, vec![(1, 0, 1), (2, 1, 1), (0, 0, 2), (0, 0, 2)]);
+        assert_eq!(heatmap.segments[8].skips, 1);
+        assert_eq!((heatmap.seeks_forward, heatmap.seeks_backward), (2, 1));
+        assert!(metrics.heatmap("b").unwrap().segments.is_empty());
+    }
+
+    // metrics::MetricsStore::prune()
+    #[test]
+    fn prune_per_video_timeout()
+    {
+        let mut long = Video::from_url("http://example.com/long.mp4");
+        long.watcher_timeout_secs = Some(120);
+        let metrics = MetricsStore::new(&Media{videos: vec![Video::from_url("http://example.com/short.mp4"), long]});
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+        metrics.report("s1", ip, "short", &WatcherReport::new(10, PlayerEvent::Progress), &Reports::default()).unwrap();
+        metrics.report("s2", ip, "long", &WatcherReport::new(10, PlayerEvent::Progress), &Reports::default()).unwrap();
+
+        let ended = metrics.prune(Utc::now().timestamp() + 60, 5);
+        assert_eq!(ended.len(), 1);
+        assert_eq!(ended[0].session, "s1");
+        assert_eq!(metrics.prune(Utc::now().timestamp() + 180, 5).len(), 1);
+
+        //at shutdown everyone goes, however recently they reported
+        metrics.report("s3", ip, "long", &WatcherReport::new(10, PlayerEvent::Progress), &Reports::default()).unwrap();
+        metrics.report("s4", ip, "short", &WatcherReport::new(10, PlayerEvent::Progress), &Reports::default()).unwrap();
+        assert_eq!(metrics.drain().len(), 2);
+        assert!(metrics.snapshot().videos.iter().all(|v| v.watchers.is_empty()));
+    }
+}
@@ -0,0 +1,310 @@
+use actix_web::HttpRequest;
+use actix_web::http::header::HeaderMap;
+
+use std::convert::TryFrom;
+use std::fmt;
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+use std::str::FromStr;
+
+/// How many leading bits of an IPv6 address are taken to identify one client, since a whole /64 usually goes to one home or device to pick addresses from.
+const IPV6_CLIENT_PREFIX: u8 = 64;
+
+/**
+A block of IP addresses in CIDR notation, like `10.0.0.0/8` or `fd00::/8`.
+A bare address is accepted too, and means just that one address.
+*/
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+#[serde(try_from = "String", into = "String")]
+pub struct Cidr
+{
+    addr: IpAddr,
+    prefix: u8
+}
+
+impl Cidr
+{
+    /**
+    Check whether an address falls within this block. IPv4 addresses mapped into IPv6 (`::ffff:a.b.c.d`)
+    are treated as the IPv4 address they contain, since that's how a dual-stack listener reports IPv4 peers.
+
+    # Examples
+    ```
+    use metric_videos::net::Cidr;
+    let lan: Cidr = "192.168.0.0/16".parse().unwrap();
+    assert!(lan.contains("192.168.4.20".parse().unwrap()));
+    assert!(lan.contains("::ffff:192.168.4.20".parse().unwrap()));
+    assert!(!lan.contains("10.0.0.1".parse().unwrap()));
+    ```
+    */
+    pub fn contains(&self, ip: IpAddr) -> bool
+    {
+        match (self.addr, unmap(ip))
+        {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => prefix_matches(&net.octets(), &ip.octets(), self.prefix),
+            (IpAddr::V6(net), IpAddr::V6(ip)) => prefix_matches(&net.octets(), &ip.octets(), self.prefix),
+            _ => false
+        }
+    }
+}
+
+impl FromStr for Cidr
+{
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err>
+    {
+        let (addr, prefix) = match s.split_once('/')
+        {
+            Some((a, p)) => (a, Some(p)),
+            None => (s, None)
+        };
+        let addr: IpAddr = addr.trim().parse().map_err(|_| format!("Invalid address in CIDR block: {}", s))?;
+        let max = if addr.is_ipv4() {32} else {128};
+        let prefix = match prefix
+        {
+            Some(p) => p.trim().parse::<u8>().ok().filter(|p| *p <= max).ok_or_else(|| format!("Invalid prefix length in CIDR block: {}", s))?,
+            None => max
+        };
+        Ok(Cidr{addr, prefix})
+    }
+}
+
+impl TryFrom<String> for Cidr
+{
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error>
+    {
+        s.parse()
+    }
+}
+
+impl From<Cidr> for String
+{
+    fn from(c: Cidr) -> Self
+    {
+        c.to_string()
+    }
+}
+
+impl fmt::Display for Cidr
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+fn unmap(ip: IpAddr) -> IpAddr
+{
+    match ip
+    {
+        IpAddr::V6(v6) => match v6.octets()
+        {
+            [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xff, 0xff, a, b, c, d] => IpAddr::from([a, b, c, d]),
+            _ => ip
+        },
+        _ => ip
+    }
+}
+
+/**
+The block of addresses that all belong to the same client as an address, for counting requests per client:
+for IPv6 that's its /64, from which one client can pick a new address whenever it likes, and for IPv4 just the address.
+
+# Examples
+```
+use metric_videos::net::client_block;
+assert_eq!(client_block("2001:db8:1:2:aaaa::1".parse().unwrap()), client_block("2001:db8:1:2:bbbb::2".parse().unwrap()));
+assert_eq!(client_block("2001:db8:1:2:aaaa::1".parse().unwrap()).to_string(), "2001:db8:1:2::/64");
+assert_eq!(client_block("::ffff:192.0.2.1".parse().unwrap()).to_string(), "192.0.2.1/32");
+```
+*/
+pub fn client_block(ip: IpAddr) -> Cidr
+{
+    match unmap(ip)
+    {
+        IpAddr::V6(v6) => {
+            let mut octets = v6.octets();
+            for octet in octets.iter_mut().skip(usize::from(IPV6_CLIENT_PREFIX / 8))
+            {
+                *octet = 0;
+            }
+            Cidr{addr: IpAddr::V6(Ipv6Addr::from(octets)), prefix: IPV6_CLIENT_PREFIX}
+        },
+        v4 => Cidr{addr: v4, prefix: 32}
+    }
+}
+
+fn prefix_matches(net: &[u8], ip: &[u8], prefix: u8) -> bool
+{
+    let (whole, rest) = ((prefix / 8) as usize, prefix % 8);
+    if net[..whole] != ip[..whole]
+    {
+        return false;
+    }
+    if rest == 0
+    {
+        return true;
+    }
+    let mask = 0xffu8 << (8 - rest);
+    net[whole] & mask == ip[whole] & mask
+}
+
+/**
+Determine the real address of the client that made a request.
+
+When the connection comes from one of our trusted proxies, the proxy chain it reports in the `Forwarded` header
+(or failing that, `X-Forwarded-For`) is walked from the nearest hop outward, skipping over trusted proxies,
+and the first untrusted address is taken as the client. Anything else just gets the address of the connection,
+since an untrusted peer could put whatever it wants in those headers.
+
+# Parameters
+- `req`: the request
+- `trusted`: blocks of addresses belonging to proxies we trust to report the client address
+
+# Returns
+The client address, or None if actix couldn't tell us who's on the other end of the connection.
+*/
+pub fn client_ip(req: &HttpRequest, trusted: &[Cidr]) -> Option<IpAddr>
+{
+    let peer = req.peer_addr()?.ip();
+    Some(resolve_client(peer, req.headers(), trusted))
+}
+
+/**
+The workings of `client_ip`, for when there's no `HttpRequest` to hand, as in middleware.
+
+# Parameters
+- `peer`: address of the other end of the connection
+- `headers`: headers of the request
+- `trusted`: blocks of addresses belonging to proxies we trust to report the client address
+*/
+pub fn resolve_client(peer: IpAddr, headers: &HeaderMap, trusted: &[Cidr]) -> IpAddr
+{
+    let is_trusted = |ip: IpAddr| trusted.iter().any(|c| c.contains(ip));
+    if !is_trusted(peer)
+    {
+        return peer;
+    }
+
+    let mut client = peer;
+    for hop in forwarded_chain(headers).into_iter().rev()
+    {
+        match hop
+        {
+            Some(ip) => {
+                client = ip;
+                if !is_trusted(ip)
+                {
+                    break;
+                }
+            },
+            None => break //a hop we can't read: the last one we could is as close as we can get
+        }
+    }
+    client
+}
+
+/**
+List the client and proxy addresses recorded in the forwarding headers, farthest from us first.
+An entry is None if that hop was given as something other than an address, like "unknown" or an obfuscated identifier.
+*/
+fn forwarded_chain(headers: &HeaderMap) -> Vec<Option<IpAddr>>
+{
+    let mut chain = Vec::new();
+    let forwarded: Vec<&str> = headers.get_all("forwarded").filter_map(|v| v.to_str().ok()).collect();
+    if !forwarded.is_empty()
+    {
+        for element in forwarded.iter().flat_map(|v| v.split(','))
+        {
+            let node = element.split(';')
+                .filter_map(|pair| match pair.split_once('=')
+                {
+                    Some((k, v)) if k.trim().eq_ignore_ascii_case("for") => Some(v.trim()),
+                    _ => None
+                })
+                .next();
+            if let Some(node) = node
+            {
+                chain.push(parse_node(node.trim_matches('"')));
+            }
+        }
+        return chain;
+    }
+
+    for v in headers.get_all("x-forwarded-for").filter_map(|v| v.to_str().ok())
+    {
+        for node in v.split(',')
+        {
+            chain.push(parse_node(node.trim()));
+        }
+    }
+    chain
+}
+
+/// Parse one hop, which may be a bare address or include a port, with IPv6 possibly in brackets.
+fn parse_node(node: &str) -> Option<IpAddr>
+{
+    if let Ok(ip) = node.parse::<IpAddr>()
+    {
+        return Some(ip);
+    }
+    if let Ok(sa) = node.parse::<SocketAddr>()
+    {
+        return Some(sa.ip());
+    }
+    node.strip_prefix('[').and_then(|n| n.split(']').next()).and_then(|n| n.parse().ok())
+        .or_else(|| node.rsplit_once(':').and_then(|(n, _port)| n.parse().ok()))
+}
+
+/*
+Test resolving addresses through proxy headers, which needs requests built by hand
+*/
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    fn trusted() -> Vec<Cidr>
+    {
+        vec!["127.0.0.1".parse().unwrap(), "10.0.0.0/8".parse().unwrap(), "::1/128".parse().unwrap()]
+    }
+
+    // net::client_ip()
+    #[test]
+    fn client_behind_proxies()
+    {
+        let direct = TestRequest::default().peer_addr("203.0.113.5:4000".parse().unwrap())
+            .header("X-Forwarded-For", "198.51.100.1").to_http_request();
+        assert_eq!(client_ip(&direct, &trusted()), Some("203.0.113.5".parse().unwrap()));
+
+        let xff = TestRequest::default().peer_addr("127.0.0.1:4000".parse().unwrap())
+            .header("X-Forwarded-For", "198.51.100.1, 203.0.113.9, 10.1.2.3").to_http_request();
+        assert_eq!(client_ip(&xff, &trusted()), Some("203.0.113.9".parse().unwrap()));
+
+        let fwd = TestRequest::default().peer_addr("127.0.0.1:4000".parse().unwrap())
+            .header("Forwarded", "for=\"[2001:db8::7]:4711\";proto=https, for=10.0.0.2")
+            .header("X-Forwarded-For", "198.51.100.1").to_http_request();
+        assert_eq!(client_ip(&fwd, &trusted()), Some("2001:db8::7".parse().unwrap()));
+
+        let unknown = TestRequest::default().peer_addr("127.0.0.1:4000".parse().unwrap())
+            .header("Forwarded", "for=198.51.100.1, for=unknown, for=10.0.0.2").to_http_request();
+        assert_eq!(client_ip(&unknown, &trusted()), Some("10.0.0.2".parse().unwrap()));
+
+        let none = TestRequest::default().peer_addr("[::1]:4000".parse().unwrap()).to_http_request();
+        assert_eq!(client_ip(&none, &trusted()), Some("::1".parse().unwrap()));
+    }
+
+    // net::Cidr::from_str()
+    #[test]
+    fn cidr_parse()
+    {
+        assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+        assert!("nonsense/8".parse::<Cidr>().is_err());
+        assert_eq!("fd00::/8".parse::<Cidr>().unwrap().to_string(), "fd00::/8");
+        assert!("fd00::/8".parse::<Cidr>().unwrap().contains("fdab::1".parse().unwrap()));
+        assert!("0.0.0.0/0".parse::<Cidr>().unwrap().contains("8.8.8.8".parse().unwrap()));
+    }
+}
@@ -0,0 +1,192 @@
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::metrics::{PlayerState, SiteTally};
+use crate::ratelimit::Limiter;
+
+/**
+Reasons a watcher report can be turned away, each counted separately.
+*/
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Rejection
+{
+    /// The report named a video we don't have
+    InvalidVideo,
+    /// The report had a missing, forged or expired playback token
+    BadToken,
+    /// The report claimed more than 100 percent
+    OutOfRange,
+    /// The report came too soon after the watcher's last one
+    TooFrequent,
+    /// The report couldn't be read, or had something in it no real player would send
+    Malformed
+}
+
+impl Rejection
+{
+    const ALL: [Rejection; 5] = [Rejection::InvalidVideo, Rejection::BadToken, Rejection::OutOfRange, Rejection::TooFrequent, Rejection::Malformed];
+
+    fn label(self) -> &'static str
+    {
+        match self
+        {
+            Rejection::InvalidVideo => "invalid_video",
+            Rejection::BadToken => "bad_token",
+            Rejection::OutOfRange => "out_of_range",
+            Rejection::TooFrequent => "too_frequent",
+            Rejection::Malformed => "malformed"
+        }
+    }
+}
+
+/**
+Running totals of watcher reports since startup.
+*/
+pub struct ReportCounters
+{
+    received: AtomicU64,
+    rejected: [AtomicU64; 5],
+    implausible: AtomicU64
+}
+
+impl ReportCounters
+{
+    /// Count a report arriving, whether or not it ends up being accepted.
+    pub fn received(&self)
+    {
+        self.received.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count a report being turned away.
+    pub fn rejected(&self, reason: Rejection)
+    {
+        self.rejected[reason as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /**
+    Count a report that was accepted, but claimed more progress than could have been watched since the last one.
+    Its position is still shown, since the watcher may have skipped ahead, but it doesn't count toward how far they got.
+    */
+    pub fn implausible(&self)
+    {
+        self.implausible.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+pub static REPORTS: ReportCounters = ReportCounters{
+    received: AtomicU64::new(0),
+    rejected: [AtomicU64::new(0), AtomicU64::new(0), AtomicU64::new(0), AtomicU64::new(0), AtomicU64::new(0)],
+    implausible: AtomicU64::new(0)
+};
+
+/// Upper bounds of the buckets of the watcher percent histogram.
+const PERCENT_BUCKETS: [u8; 10] = [10, 20, 30, 40, 50, 60, 70, 80, 90, 100];
+
+/**
+Render the current state in the Prometheus text exposition format.
+
+# Parameters
+- `tally`: counts of the live watchers to export gauges and histograms from
+
+# Returns
+The body of a scrape response.
+*/
+pub fn render(tally: &SiteTally) -> String
+{
+    let mut out = String::new();
+
+    out.push_str("# HELP metric_videos_active_watchers Watchers currently reporting on each video.\n");
+    out.push_str("# TYPE metric_videos_active_watchers gauge\n");
+    for video in &tally.videos
+    {
+        let _ = writeln!(out, "metric_videos_active_watchers{{video=\"{}\"}} {}", video.id, video.watchers);
+    }
+
+    out.push_str("# HELP metric_videos_watchers_by_state Watchers currently reporting on each video, by what their player is doing.\n");
+    out.push_str("# TYPE metric_videos_watchers_by_state gauge\n");
+    for video in &tally.videos
+    {
+        for state in PlayerState::ALL.iter()
+        {
+            let _ = writeln!(out, "metric_videos_watchers_by_state{{video=\"{}\",state=\"{}\"}} {}", video.id, state.label(), video.by_state[*state as usize]);
+        }
+    }
+
+    out.push_str("# HELP metric_videos_distinct_ips Distinct client addresses among all current watchers.\n");
+    out.push_str("# TYPE metric_videos_distinct_ips gauge\n");
+    let _ = writeln!(out, "metric_videos_distinct_ips {}", tally.distinct_ips);
+
+    out.push_str("# HELP metric_videos_watcher_percent How far into the video each current watcher is.\n");
+    out.push_str("# TYPE metric_videos_watcher_percent histogram\n");
+    for video in &tally.videos
+    {
+        for bound in PERCENT_BUCKETS.iter()
+        {
+            let count: u64 = video.by_percent[..=usize::from(*bound)].iter().sum();
+            let _ = writeln!(out, "metric_videos_watcher_percent_bucket{{video=\"{}\",le=\"{}\"}} {}", video.id, bound, count);
+        }
+        let sum: u64 = video.by_percent.iter().enumerate().map(|(p, count)| p as u64 * count).sum();
+        let _ = writeln!(out, "metric_videos_watcher_percent_bucket{{video=\"{}\",le=\"+Inf\"}} {}", video.id, video.watchers);
+        let _ = writeln!(out, "metric_videos_watcher_percent_sum{{video=\"{}\"}} {}", video.id, sum);
+        let _ = writeln!(out, "metric_videos_watcher_percent_count{{video=\"{}\"}} {}", video.id, video.watchers);
+    }
+
+    out.push_str("# HELP metric_videos_reports_received_total Watcher reports received.\n");
+    out.push_str("# TYPE metric_videos_reports_received_total counter\n");
+    let _ = writeln!(out, "metric_videos_reports_received_total {}", REPORTS.received.load(Ordering::Relaxed));
+
+    out.push_str("# HELP metric_videos_reports_rejected_total Watcher reports turned away, by reason.\n");
+    out.push_str("# TYPE metric_videos_reports_rejected_total counter\n");
+    for reason in Rejection::ALL.iter()
+    {
+        let _ = writeln!(out, "metric_videos_reports_rejected_total{{reason=\"{}\"}} {}", reason.label(), REPORTS.rejected[*reason as usize].load(Ordering::Relaxed));
+    }
+
+    out.push_str("# HELP metric_videos_reports_implausible_total Watcher reports accepted, but not counted toward how far the watcher got, for skipping ahead.\n");
+    out.push_str("# TYPE metric_videos_reports_implausible_total counter\n");
+    let _ = writeln!(out, "metric_videos_reports_implausible_total {}", REPORTS.implausible.load(Ordering::Relaxed));
+
+    out.push_str("# HELP metric_videos_rate_limited_total Requests turned away for coming too fast, by group of endpoints.\n");
+    out.push_str("# TYPE metric_videos_rate_limited_total counter\n");
+    for limiter in Limiter::ALL.iter()
+    {
+        let _ = writeln!(out, "metric_videos_rate_limited_total{{limit=\"{}\"}} {}", limiter.label(), limiter.limited());
+    }
+
+    out
+}
+
+/*
+Test the exposition format against counts built by hand
+*/
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::metrics::VideoTally;
+
+    // prometheus::render()
+    #[test]
+    fn render_histogram()
+    {
+        let mut intro = VideoTally::new("intro");
+        intro.add(PlayerState::Playing, 15);
+        intro.add(PlayerState::Paused, 95);
+        let tally = SiteTally{videos: vec![intro, VideoTally::new("outro")], distinct_ips: 1};
+
+        let text = render(&tally);
+        assert!(text.contains("metric_videos_active_watchers{video=\"intro\"} 2\n"));
+        assert!(text.contains("metric_videos_active_watchers{video=\"outro\"} 0\n"));
+        assert!(text.contains("metric_videos_distinct_ips 1\n"));
+        assert!(text.contains("metric_videos_watchers_by_state{video=\"intro\",state=\"paused\"} 1\n"));
+        assert!(text.contains("metric_videos_watchers_by_state{video=\"outro\",state=\"playing\"} 0\n"));
+        assert!(text.contains("metric_videos_watcher_percent_bucket{video=\"intro\",le=\"10\"} 0\n"));
+        assert!(text.contains("metric_videos_watcher_percent_bucket{video=\"intro\",le=\"20\"} 1\n"));
+        assert!(text.contains("metric_videos_watcher_percent_bucket{video=\"intro\",le=\"+Inf\"} 2\n"));
+        assert!(text.contains("metric_videos_watcher_percent_sum{video=\"intro\"} 110\n"));
+        assert!(text.contains("metric_videos_reports_rejected_total{reason=\"invalid_video\"}"));
+        assert!(!text.contains("metric_videos_reports_rejected_total{reason=\"implausible\"}"));
+        assert!(text.contains("metric_videos_reports_implausible_total "));
+        assert!(text.contains("metric_videos_rate_limited_total{limit=\"watcher\"}"));
+    }
+}
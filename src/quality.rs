@@ -0,0 +1,127 @@
+use std::collections::BTreeMap;
+
+/// Longest player error code we keep, so a misbehaving player can't fill memory with them.
+const MAX_ERROR_LEN: usize = 32;
+
+/**
+How playback is going for one watcher, as measured by their player.
+Each report carries the totals since the player started on the video, replacing the ones before.
+Players that don't measure something leave it out.
+*/
+#[derive(Deserialize, Serialize, Clone, Debug, Default, PartialEq)]
+#[serde(default)]
+pub struct Quality
+{
+    /// Milliseconds from asking to play to the first frame showing
+    pub startup_ms: Option<u32>,
+    /// How many times playback has stalled to buffer after it started
+    pub rebuffers: u32,
+    /// How many milliseconds those stalls took altogether
+    pub rebuffer_ms: u64,
+    /// Size of the picture being played, in pixels
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    /// Bitrate of the encoding being played, for players that switch between several
+    pub bitrate_kbps: Option<u32>,
+    /// Code of the last error the player hit, like "MEDIA_ERR_DECODE"
+    pub error: Option<String>
+}
+
+impl Quality
+{
+    /**
+    Check for values no real player would send.
+
+    # Errors
+    A description of the first problem found: an error code that's too long or has anything but letters, digits, `_`, `-` and `.` in it.
+    */
+    pub fn validate(&self) -> Result<(), String>
+    {
+        if let Some(error) = &self.error
+        {
+            if error.is_empty() || error.len() > MAX_ERROR_LEN || !error.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-' || c == '.')
+            {
+                return Err(String::from("malformed error code"));
+            }
+        }
+        Ok(())
+    }
+
+    /// The picture size written like "1280x720", if the player said.
+    fn resolution(&self) -> Option<String>
+    {
+        Some(format!("{}x{}", self.width?, self.height?))
+    }
+}
+
+/**
+How playback is going for the current watchers of one video altogether.
+*/
+#[derive(Serialize, Clone, Debug, Default, PartialEq)]
+pub struct QualitySummary
+{
+    /// Mean time to first frame, among watchers whose players measured it
+    pub startup_ms: Option<u32>,
+    /// Stalls, and milliseconds spent stalled, by all the watchers together
+    pub rebuffers: u32,
+    pub rebuffer_ms: u64,
+    /// Mean bitrate being played, among watchers whose players said
+    pub bitrate_kbps: Option<u32>,
+    /// How many watchers are playing at each picture size, like "1280x720"
+    pub resolutions: BTreeMap<String, u32>,
+    /// How many watchers last hit each player error code
+    pub errors: BTreeMap<String, u32>
+}
+
+impl QualitySummary
+{
+    /**
+    Add up how playback is going for a group of watchers.
+
+    # Examples
+    ```
+    use metric_videos::quality::{Quality, QualitySummary};
+    let a = Quality{startup_ms: Some(400), rebuffers: 1, rebuffer_ms: 900, width: Some(1280), height: Some(720), ..Quality::default()};
+    let b = Quality{startup_ms: Some(800), error: Some(String::from("MEDIA_ERR_DECODE")), ..Quality::default()};
+    let summary = QualitySummary::of(vec![&a, &b]);
+    assert_eq!(summary.startup_ms, Some(600));
+    assert_eq!((summary.rebuffers, summary.rebuffer_ms), (1, 900));
+    assert_eq!(summary.bitrate_kbps, None);
+    assert_eq!(summary.resolutions["1280x720"], 1);
+    assert_eq!(summary.errors["MEDIA_ERR_DECODE"], 1);
+    ```
+    */
+    pub fn of<'a, I>(qualities: I) -> Self
+        where I: IntoIterator<Item = &'a Quality>
+    {
+        let mut summary = QualitySummary::default();
+        let (mut startups, mut bitrates): (Vec<u32>, Vec<u32>) = (Vec::new(), Vec::new());
+        for q in qualities
+        {
+            summary.rebuffers = summary.rebuffers.saturating_add(q.rebuffers);
+            summary.rebuffer_ms = summary.rebuffer_ms.saturating_add(q.rebuffer_ms);
+            startups.extend(q.startup_ms);
+            bitrates.extend(q.bitrate_kbps);
+            if let Some(resolution) = q.resolution()
+            {
+                *summary.resolutions.entry(resolution).or_insert(0) += 1;
+            }
+            if let Some(error) = &q.error
+            {
+                *summary.errors.entry(error.clone()).or_insert(0) += 1;
+            }
+        }
+        summary.startup_ms = mean(&startups);
+        summary.bitrate_kbps = mean(&bitrates);
+        summary
+    }
+}
+
+fn mean(values: &[u32]) -> Option<u32>
+{
+    if values.is_empty()
+    {
+        return None;
+    }
+    Some((values.iter().map(|v| u64::from(*v)).sum::<u64>() / values.len() as u64) as u32)
+}
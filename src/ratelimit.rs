@@ -0,0 +1,411 @@
+use actix_service::{Service, Transform};
+use actix_web::{dev::ServiceRequest, dev::ServiceResponse, Error, http::header, http::StatusCode};
+use actix_http::ResponseBuilder;
+use futures::future::{ok, Either, Ready};
+use log::{warn, /*error, info, debug, trace, log, Level*/};
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::task::{Context, Poll};
+use std::time::Instant;
+
+use crate::{auth, net};
+use crate::settings::{Limit, LimitKey, SETTINGS};
+
+/// Most clients to keep track of. Past this, the ones seen longest ago are forgotten.
+const MAX_TRACKED: usize = 10_000;
+
+/// How many clients are kept when some have to be forgotten, leaving room for new ones before it has to be done again.
+const TRACKED_AFTER_EVICTION: usize = MAX_TRACKED * 9 / 10;
+
+/// When limiting by session, how many sessions' worth of requests one address may make in all, so minting new sessions doesn't get around the limit.
+const SESSIONS_PER_ADDRESS: u32 = 50;
+
+/**
+The groups of endpoints that are rate limited separately, each by its own limit in the config.
+*/
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Limiter
+{
+    Watcher,
+    Metrics,
+    Login
+}
+
+impl Limiter
+{
+    pub const ALL: [Limiter; 3] = [Limiter::Watcher, Limiter::Metrics, Limiter::Login];
+
+    pub fn label(self) -> &'static str
+    {
+        match self
+        {
+            Limiter::Watcher => "watcher",
+            Limiter::Metrics => "metrics",
+            Limiter::Login => "login"
+        }
+    }
+
+    /// How many requests this limiter has turned away since startup.
+    pub fn limited(self) -> u64
+    {
+        LIMITED[self as usize].load(Ordering::Relaxed)
+    }
+
+    /**
+    Count a request against a key of the caller's choosing, for clients told apart by something the middleware can't see, like the name in a login form.
+
+    # Errors
+    How many seconds until the key will have enough for one request, if it doesn't now.
+    */
+    pub fn take(self, key: &str) -> Result<(), u64>
+    {
+        let limit = self.limit();
+        if limit.per_second <= 0.0
+        {
+            return Ok(());
+        }
+        self.take_keys(&[(key.to_owned(), limit)])
+    }
+
+    /// Take one request's worth from this limiter's bucket of each key, counting it as turned away if any of them is out.
+    fn take_keys(self, keys: &[(String, Limit)]) -> Result<(), u64>
+    {
+        let result = match BUCKETS[self as usize].lock()
+        {
+            Ok(mut buckets) => take_all(&mut buckets, keys, Instant::now()),
+            Err(_) => Ok(()) //better to let everyone through than no one
+        };
+        if result.is_err()
+        {
+            LIMITED[self as usize].fetch_add(1, Ordering::Relaxed);
+        }
+        result
+    }
+
+    fn limit(self) -> Limit
+    {
+        let settings = SETTINGS.get();
+        match self
+        {
+            Limiter::Watcher => settings.rate_limits.watcher.clone(),
+            Limiter::Metrics => settings.rate_limits.metrics.clone(),
+            Limiter::Login => settings.rate_limits.login.clone()
+        }
+    }
+}
+
+static LIMITED: [AtomicU64; 3] = [AtomicU64::new(0), AtomicU64::new(0), AtomicU64::new(0)];
+
+/**
+How many requests one client has left, as of when it was last topped up.
+*/
+struct Bucket
+{
+    tokens: f64,
+    /// When the client was last seen, which is also when the bucket was last topped up
+    updated: Instant
+}
+
+impl Bucket
+{
+    fn full(limit: &Limit, now: Instant) -> Self
+    {
+        Bucket{tokens: f64::from(limit.burst), updated: now}
+    }
+
+    /// Top the bucket up for the time that's passed, without going over the burst size.
+    fn refill(&mut self, limit: &Limit, now: Instant)
+    {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * limit.per_second).min(f64::from(limit.burst));
+        self.updated = now;
+    }
+
+    /**
+    See whether there's one request's worth in the bucket, without taking it.
+
+    # Errors
+    How many seconds until there will be enough for one request, if there isn't now.
+    */
+    fn check(&mut self, limit: &Limit, now: Instant) -> Result<(), u64>
+    {
+        self.refill(limit, now);
+        if self.tokens >= 1.0
+        {
+            Ok(())
+        }else{
+            Err(((1.0 - self.tokens) / limit.per_second).ceil().max(1.0) as u64)
+        }
+    }
+
+    /**
+    Take one request's worth from the bucket.
+
+    # Errors
+    How many seconds until there will be enough for one request, if there isn't now.
+    */
+    fn take(&mut self, limit: &Limit, now: Instant) -> Result<(), u64>
+    {
+        self.check(limit, now)?;
+        self.tokens -= 1.0;
+        Ok(())
+    }
+}
+
+/**
+Take one request's worth from the bucket of each key a request counts against, but only if every one of them has it.
+
+# Parameters
+- `buckets`: the buckets of one limiter, keyed by client
+- `keys`: each key the request counts against, with the limit that applies to it
+- `now`: the current time
+
+# Errors
+How many seconds until every bucket will have enough for one request, if they don't now.
+*/
+fn take_all(buckets: &mut HashMap<String, Bucket>, keys: &[(String, Limit)], now: Instant) -> Result<(), u64>
+{
+    if keys.iter().any(|(key, _)| !buckets.contains_key(key)) && buckets.len() + keys.len() > MAX_TRACKED
+    {
+        evict(buckets);
+    }
+
+    let mut wait = 0;
+    for (key, limit) in keys
+    {
+        if let Err(w) = buckets.entry(key.clone()).or_insert_with(|| Bucket::full(limit, now)).check(limit, now)
+        {
+            wait = wait.max(w);
+        }
+    }
+    if wait > 0
+    {
+        return Err(wait);
+    }
+    for (key, limit) in keys
+    {
+        if let Some(bucket) = buckets.get_mut(key)
+        {
+            bucket.take(limit, now)?;
+        }
+    }
+    Ok(())
+}
+
+/**
+Forget the clients seen longest ago, to make room for new ones.
+It forgets more than it strictly has to, so it doesn't have to go through them all again for every new client.
+*/
+fn evict(buckets: &mut HashMap<String, Bucket>)
+{
+    let mut seen: Vec<Instant> = buckets.values().map(|b| b.updated).collect();
+    if seen.len() <= TRACKED_AFTER_EVICTION
+    {
+        return;
+    }
+    let cutoff_index = seen.len() - TRACKED_AFTER_EVICTION;
+    let cutoff = *seen.select_nth_unstable(cutoff_index).1;
+    buckets.retain(|_, b| b.updated > cutoff);
+}
+
+/**
+Middleware that turns away clients making requests faster than the limit configured for a group of endpoints,
+with HTTP 429 and a `Retry-After` header saying how many seconds to wait.
+
+# Examples
+```
+use actix_web::{web, App};
+use metric_videos::ratelimit::{RateLimit, Limiter};
+use metric_videos::resources::api;
+let app = App::new().service(web::resource("/api/metrics").wrap(RateLimit(Limiter::Metrics)).route(web::get().to(api::metrics)));
+```
+*/
+pub struct RateLimit(pub Limiter);
+
+impl<S> Transform<S> for RateLimit
+    where S: Service<Request = ServiceRequest, Response = ServiceResponse, Error = Error>, S::Future: 'static
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse;
+    type Error = Error;
+    type InitError = ();
+    type Transform = RateLimitMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future
+    {
+        ok(RateLimitMiddleware{service, limiter: self.0})
+    }
+}
+
+/**
+The service made by `RateLimit` around each one it guards.
+*/
+pub struct RateLimitMiddleware<S>
+{
+    service: S,
+    limiter: Limiter
+}
+
+impl<S> Service for RateLimitMiddleware<S>
+    where S: Service<Request = ServiceRequest, Response = ServiceResponse, Error = Error>, S::Future: 'static
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse;
+    type Error = Error;
+    type Future = Either<S::Future, Ready<Result<Self::Response, Self::Error>>>;
+
+    fn poll_ready(&mut self, cx: &mut Context) -> Poll<Result<(), Self::Error>>
+    {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: ServiceRequest) -> Self::Future
+    {
+        let limit = self.limiter.limit();
+        if limit.per_second <= 0.0
+        {
+            return Either::Left(self.service.call(req));
+        }
+
+        let keys = client_keys(&req, &limit);
+        match self.limiter.take_keys(&keys)
+        {
+            Ok(()) => Either::Left(self.service.call(req)),
+            Err(retry_after) => {
+                let who: Vec<&str> = keys.iter().map(|(key, _)| key.as_str()).collect();
+                warn!("Rate limiting {} on {} for {}s", who.join(", "), req.path(), retry_after);
+                let response = ResponseBuilder::new(StatusCode::TOO_MANY_REQUESTS)
+                    .set_header(header::RETRY_AFTER, retry_after.to_string())
+                    .finish();
+                Either::Right(ok(req.into_response(response)))
+            }
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct TokenQuery
+{
+    token: Option<String>
+}
+
+/**
+Work out who a request counts against, and by which limit.
+Limiting by session, a request with a valid playback token counts against the session it carries,
+and also against its address, which gets room for many sessions so minting new ones doesn't get around the limit.
+Anything else counts against its address alone: a session ID without a token is only the client's say-so.
+IPv6 addresses count by their /64, so a client can't get a fresh limit by moving to another address in its block.
+*/
+fn client_keys(req: &ServiceRequest, limit: &Limit) -> Vec<(String, Limit)>
+{
+    let address = match req.peer_addr()
+    {
+        Some(peer) => net::client_block(net::resolve_client(peer.ip(), req.headers(), &SETTINGS.get().network.trusted_proxies)).to_string(),
+        None => String::from("unknown")
+    };
+    let session = match limit.by
+    {
+        LimitKey::Ip => None,
+        LimitKey::Session => serde_urlencoded::from_str::<TokenQuery>(req.query_string()).ok()
+            .and_then(|q| q.token)
+            .and_then(|t| auth::playback_session(&t))
+    };
+    match session
+    {
+        Some(s) => {
+            let per_address = Limit{per_second: limit.per_second * f64::from(SESSIONS_PER_ADDRESS), burst: limit.burst.saturating_mul(SESSIONS_PER_ADDRESS), by: LimitKey::Ip};
+            vec![(format!("sessions at address {}", address), per_address), (format!("session {}", s), limit.clone())]
+        },
+        None => vec![(format!("address {}", address), limit.clone())]
+    }
+}
+
+lazy_static!
+{
+    /// Buckets of each limiter, keyed by client
+    static ref BUCKETS: [Mutex<HashMap<String, Bucket>>; 3] = [Mutex::new(HashMap::new()), Mutex::new(HashMap::new()), Mutex::new(HashMap::new())];
+}
+
+/*
+Test the token bucket, which doesn't need a server or config
+*/
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use std::time::Duration;
+
+    // ratelimit::Bucket::take()
+    #[test]
+    fn bucket_refill()
+    {
+        let limit = Limit{per_second: 2.0, burst: 3, by: LimitKey::Ip};
+        let start = Instant::now();
+        let mut bucket = Bucket::full(&limit, start);
+
+        assert!(bucket.take(&limit, start).is_ok());
+        assert!(bucket.take(&limit, start).is_ok());
+        assert!(bucket.take(&limit, start).is_ok());
+        assert_eq!(bucket.take(&limit, start), Err(1));
+
+        //half a second gets back one request at two a second
+        let later = start + Duration::from_millis(500);
+        assert!(bucket.take(&limit, later).is_ok());
+        assert!(bucket.take(&limit, later).is_err());
+
+        //a long wait doesn't build up more than a burst
+        let much_later = later + Duration::from_secs(60);
+        for _ in 0..3
+        {
+            assert!(bucket.take(&limit, much_later).is_ok());
+        }
+        assert!(bucket.take(&limit, much_later).is_err());
+
+        let slow = Limit{per_second: 0.1, burst: 1, by: LimitKey::Ip};
+        let mut bucket = Bucket::full(&slow, start);
+        assert!(bucket.take(&slow, start).is_ok());
+        assert_eq!(bucket.take(&slow, start), Err(10));
+    }
+
+    // ratelimit::take_all()
+    #[test]
+    fn buckets_of_every_key()
+    {
+        let session = Limit{per_second: 1.0, burst: 1, by: LimitKey::Session};
+        let address = Limit{per_second: 1.0, burst: 2, by: LimitKey::Ip};
+        let keys = |s: &str| vec![(String::from("sessions at address 192.0.2.1"), address.clone()), (format!("session {}", s), session.clone())];
+        let now = Instant::now();
+        let mut buckets = HashMap::new();
+
+        assert!(take_all(&mut buckets, &keys("a"), now).is_ok());
+        //a session out of requests doesn't use up any of its address's
+        assert_eq!(take_all(&mut buckets, &keys("a"), now), Err(1));
+        assert!(take_all(&mut buckets, &keys("b"), now).is_ok());
+        //but a new session can't get past an address that's out of them
+        assert_eq!(take_all(&mut buckets, &keys("c"), now), Err(1));
+    }
+
+    // ratelimit::evict()
+    #[test]
+    fn forget_least_recently_seen()
+    {
+        let limit = Limit{per_second: 1.0, burst: 5, by: LimitKey::Ip};
+        let start = Instant::now();
+        let mut buckets = HashMap::new();
+        for i in 0..MAX_TRACKED
+        {
+            let key = vec![(format!("address {}", i), limit.clone())];
+            assert!(take_all(&mut buckets, &key, start + Duration::from_millis(i as u64)).is_ok());
+        }
+        assert_eq!(buckets.len(), MAX_TRACKED);
+
+        let later = start + Duration::from_secs(60);
+        assert!(take_all(&mut buckets, &[(String::from("address new"), limit.clone())], later).is_ok());
+        assert!(buckets.len() <= TRACKED_AFTER_EVICTION + 1);
+        assert!(buckets.contains_key("address new") && buckets.contains_key(&format!("address {}", MAX_TRACKED - 1)));
+        assert!(!buckets.contains_key("address 0"));
+    }
+}
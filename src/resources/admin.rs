@@ -0,0 +1,176 @@
+use actix_web::{web, HttpRequest, HttpResponse, error::BlockingError, http::header, http::StatusCode};
+use actix_http::ResponseBuilder;
+use log::{error, info, /*warn, debug, trace, log, Level*/};
+
+use std::sync::Mutex;
+
+use crate::auth::Identity;
+use crate::metrics::apply_settings;
+use crate::settings::{SETTINGS, Settings, Video};
+
+lazy_static!
+{
+    /// Held for the whole read-modify-write of a catalog change, so concurrent edits can't undo each other
+    static ref CATALOG_EDIT: Mutex<()> = Mutex::new(());
+}
+
+/**
+Responds to requests to add a video to the catalog, at the api endpoint admin/videos
+
+# Parameters
+- `video`: actix-generated struct parsed from the JSON request body, in the same form as a catalog entry in the config
+
+# Returns
+HttpResponse containing the added video as JSON.
+
+# Errors
+The HTTP status code can indicate failure: 401/403 if not signed in as an admin, 409 if the id is taken or the catalog was given on the command line,
+400 if the video is invalid, 500 if the config couldn't be saved. Failures carry a JSON body with an "error" message.
+*/
+pub async fn add_video(req: HttpRequest, video: web::Json<Video>) -> HttpResponse
+{
+    let video = video.into_inner();
+    let entry = video.clone();
+
+    edit_catalog(&req, move |settings| {
+        if settings.media.videos.iter().any(|v| v.id == entry.id)
+        {
+            return Err((StatusCode::CONFLICT, format!("Video id \"{}\" is already in use", entry.id)));
+        }
+        settings.media.videos.push(entry);
+        Ok(())
+    }, StatusCode::CREATED, &video).await
+}
+
+/**
+Responds to requests to replace a video in the catalog, at the api endpoint admin/videos/{id}
+
+# Parameters
+- `video_id`: actix-generated struct containing the captured parameter "id" (video id)
+- `video`: actix-generated struct parsed from the JSON request body. Its id must match the one in the path.
+
+# Returns
+HttpResponse containing the updated video as JSON.
+
+# Errors
+The HTTP status code can indicate failure: 401/403 if not signed in as an admin, 404 if there's no such video,
+409 if the catalog was given on the command line,
+400 if the video is invalid, 500 if the config couldn't be saved. Failures carry a JSON body with an "error" message.
+*/
+pub async fn update_video(req: HttpRequest, video_id: web::Path<String>, video: web::Json<Video>) -> HttpResponse
+{
+    let video = video.into_inner();
+    if video.id != *video_id
+    {
+        return error_response(StatusCode::BAD_REQUEST, "The video id can't be changed");
+    }
+
+    let entry = video.clone();
+    edit_catalog(&req, move |settings| {
+        match settings.media.videos.iter_mut().find(|v| v.id == entry.id)
+        {
+            Some(v) => {
+                *v = entry;
+                Ok(())
+            },
+            None => Err((StatusCode::NOT_FOUND, format!("No video with id \"{}\"", entry.id)))
+        }
+    }, StatusCode::OK, &video).await
+}
+
+/**
+Responds to requests to remove a video from the catalog, at the api endpoint admin/videos/{id}.
+Anyone watching it is ended right away.
+
+# Parameters
+- `video_id`: actix-generated struct containing the captured parameter "id" (video id)
+
+# Returns
+HttpResponse with a blank body.
+
+# Errors
+The HTTP status code can indicate failure: 401/403 if not signed in as an admin, 404 if there's no such video,
+409 if the catalog was given on the command line,
+500 if the config couldn't be saved. Failures carry a JSON body with an "error" message.
+*/
+pub async fn delete_video(req: HttpRequest, video_id: web::Path<String>) -> HttpResponse
+{
+    let video_id = video_id.into_inner();
+    let response = edit_catalog(&req, move |settings| {
+        let before = settings.media.videos.len();
+        settings.media.videos.retain(|v| v.id != video_id);
+        if settings.media.videos.len() == before
+        {
+            return Err((StatusCode::NOT_FOUND, format!("No video with id \"{}\"", video_id)));
+        }
+        Ok(())
+    }, StatusCode::NO_CONTENT, &()).await;
+
+    if response.status() == StatusCode::NO_CONTENT
+    {
+        return ResponseBuilder::new(StatusCode::NO_CONTENT).finish();
+    }
+    response
+}
+
+/**
+Apply a change to a copy of the current settings, then validate it, save it to the config file and put it into effect.
+Taking the lock, writing the config file and recording the sessions of anyone watching a removed video all block,
+so it's done off the worker thread.
+
+# Parameters
+- `req`: the request asking for the change, for logging who made it
+- `change`: makes the change, or says why it can't with a status code and message
+- `success`: status code to respond with if everything works out
+- `body`: what to respond with if everything works out, as JSON
+
+# Returns
+HttpResponse reporting how it went.
+*/
+async fn edit_catalog<F, T>(req: &HttpRequest, change: F, success: StatusCode, body: &T) -> HttpResponse
+    where F: FnOnce(&mut Settings) -> Result<(), (StatusCode, String)> + Send + 'static, T: serde::Serialize
+{
+    let who = req.extensions().get::<Identity>().map_or_else(|| String::from("someone"), |i| i.name.clone());
+    let result = web::block(move || {
+        let _edit = match CATALOG_EDIT.lock()
+        {
+            Ok(g) => g,
+            Err(poisoned) => poisoned.into_inner()
+        };
+
+        //the catalog in effect isn't the one in the file, so there'd be no good way to save the change
+        if Settings::catalog_overridden()
+        {
+            return Err((StatusCode::CONFLICT, String::from("The catalog was given on the command line, so it can't be changed here")));
+        }
+
+        let mut settings = (*SETTINGS.get()).clone();
+        change(&mut settings)?;
+        settings.media.validate().map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+        if let Err(e) = settings.media.save()
+        {
+            error!("Couldn't save config after catalog change: {}", e);
+            return Err((StatusCode::INTERNAL_SERVER_ERROR, String::from("Couldn't save the config file")));
+        }
+        let video_count = settings.media.videos.len();
+        apply_settings(settings);
+        info!("Video catalog changed by {} through the admin API, now offering {} video(s)", who, video_count);
+        Ok(())
+    }).await;
+
+    match result
+    {
+        Ok(()) => ResponseBuilder::new(success)
+            .set_header(header::CONTENT_TYPE, "application/json; charset=utf-8")
+            .json(body),
+        Err(BlockingError::Error((status, msg))) => error_response(status, &msg),
+        Err(BlockingError::Canceled) => error_response(StatusCode::INTERNAL_SERVER_ERROR, "Couldn't change the catalog")
+    }
+}
+
+fn error_response(status: StatusCode, msg: &str) -> HttpResponse
+{
+    ResponseBuilder::new(status)
+        .set_header(header::CONTENT_TYPE, "application/json; charset=utf-8")
+        .json(serde_json::json!({"error": msg}))
+}
@@ -1,11 +1,193 @@
-use actix_web::{web, HttpRequest, HttpResponse, http::header, http::StatusCode};
-use actix_http::ResponseBuilder;
-use log::{/*error, */warn, /*info, debug, trace, log, Level*/};
+use actix_codec::{Decoder, Encoder};
+use actix_web::{web, HttpMessage, HttpRequest, HttpResponse, ResponseError, http::header, http::StatusCode};
+use actix_http::{ResponseBuilder, ws};
+use bytes::{Bytes, BytesMut};
+use chrono::{NaiveDate, Utc};
+use futures::channel::mpsc::{unbounded, UnboundedSender};
+use futures::StreamExt;
+use log::{error, warn, /*info, debug, trace, log, Level*/};
 
+use std::collections::HashMap;
 use std::net::{IpAddr};
+use std::sync::Arc;
 
-use crate::metrics::METRICS;
+use crate::auth;
+use crate::events;
+use crate::export::ExportFormat;
+use crate::history;
+use crate::metrics::{ClientInfo, METRICS, PlayerEvent, POSITION_SLACK_SECS, WatcherReport};
+use crate::net;
+use crate::prometheus::{self, REPORTS, Rejection};
+use crate::quality::Quality;
+use crate::settings::SETTINGS;
+use crate::summary;
+use crate::timeseries::{self, TIMESERIES, TimeseriesQuery};
 
+/// Name of the cookie that can carry the session ID, for clients that don't put it in the query string.
+const SESSION_COOKIE: &str = "mv_session";
+
+/// Most sessions to read into memory at once while exporting, each batch going out as one chunk of the response.
+const EXPORT_BATCH: usize = 1000;
+
+/**
+Query string accepted by the watcher endpoints, identifying which player is reporting.
+*/
+#[derive(Deserialize)]
+pub struct WatcherSession
+{
+    /// Playback token handed out with the player page, which also decides the session ID
+    pub token: Option<String>,
+    /// Session ID chosen by the player itself, only accepted while tokens aren't required
+    pub session: Option<String>,
+    /// What just happened in the player, for the HTTP endpoints. Ignored when opening a WebSocket, whose messages carry their own.
+    #[serde(default)]
+    pub event: PlayerEvent
+}
+
+/**
+Body of a report to the v2 watcher endpoint, which carries everything as JSON instead of in the path.
+*/
+#[derive(Deserialize)]
+pub struct WatcherReportBody
+{
+    /// Catalog id of the video being watched
+    pub video: String,
+    /// How many seconds into the video the watcher is
+    pub position: f64,
+    /// Length of the video in seconds, as the player sees it. If left out, the catalog's is used.
+    #[serde(default)]
+    pub duration: Option<f64>,
+    /// What just happened in the player, if anything
+    #[serde(default)]
+    pub event: PlayerEvent,
+    /// How playback is going, from players that measure it
+    #[serde(default)]
+    pub quality: Option<Quality>,
+    /// What the watcher is watching with
+    #[serde(default)]
+    pub client: Option<ClientInfo>
+}
+
+impl WatcherReportBody
+{
+    /**
+    Check the report over, and turn it into one the live metrics can take, with the duration filled in from the catalog if the player didn't say.
+
+    # Parameters
+    - `catalog_duration`: length of the video in seconds according to the catalog, for when the player doesn't say
+
+    # Errors
+    Every problem found, each with the field it's in.
+
+    # Examples
+    ```
+    use metric_videos::resources::api::WatcherReportBody;
+    let body: WatcherReportBody = serde_json::from_str(r#"{"video": "intro", "position": 30.5, "event": "pause"}"#).unwrap();
+    let report = body.validate(Some(61)).unwrap();
+    assert_eq!((report.position, report.duration), (Some(30.5), Some(61.0)));
+
+    let body: WatcherReportBody = serde_json::from_str(r#"{"video": "intro", "position": -1, "duration": 0}"#).unwrap();
+    let fields: Vec<_> = body.validate(None).unwrap_err().into_iter().map(|e| e.field).collect();
+    assert_eq!(fields, vec![Some("position"), Some("duration")]);
+    ```
+    */
+    pub fn validate(self, catalog_duration: Option<u32>) -> Result<WatcherReport, Vec<FieldError>>
+    {
+        let mut errors: Vec<FieldError> = Vec::new();
+        if self.position < 0.0
+        {
+            errors.push(FieldError::new("position", "must not be negative"));
+        }
+        let duration = match (self.duration, catalog_duration)
+        {
+            (Some(d), _) if d <= 0.0 => {
+                errors.push(FieldError::new("duration", "must be more than zero"));
+                None
+            },
+            (Some(d), _) => Some(d),
+            (None, Some(d)) if d > 0 => Some(f64::from(d)),
+            (None, _) => {
+                errors.push(FieldError::new("duration", "is needed, since the catalog doesn't say how long this video is"));
+                None
+            }
+        };
+        if let Some(d) = duration
+        {
+            if self.position > d + POSITION_SLACK_SECS
+            {
+                errors.push(FieldError::new("position", "is past the end of the video"));
+            }
+        }
+        if let Some(Err(e)) = self.quality.as_ref().map(Quality::validate)
+        {
+            errors.push(FieldError::new("quality", &e));
+        }
+        if let Some(Err(e)) = self.client.as_ref().map(ClientInfo::validate)
+        {
+            errors.push(FieldError::new("client", &e));
+        }
+
+        match duration
+        {
+            Some(d) if errors.is_empty() => Ok(WatcherReport{
+                percent: None, position: Some(self.position), duration: Some(d), event: self.event, quality: self.quality, client: self.client
+            }),
+            _ => Err(errors)
+        }
+    }
+}
+
+/**
+One problem with a report, as sent back by the v2 watcher endpoint.
+*/
+#[derive(Serialize, Debug, PartialEq)]
+pub struct FieldError
+{
+    /// Which field of the body the problem is in, or none if it's with the report as a whole
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub field: Option<&'static str>,
+    pub message: String
+}
+
+impl FieldError
+{
+    fn new(field: &'static str, message: &str) -> Self
+    {
+        FieldError{field: Some(field), message: message.to_owned()}
+    }
+}
+
+/**
+Query string accepted by the history endpoint. Both ends of the range are unix timestamps matched against session end times.
+*/
+#[derive(Deserialize)]
+pub struct HistoryRange
+{
+    pub from: Option<i64>,
+    pub to: Option<i64>
+}
+
+/**
+Query string accepted by the summary endpoint.
+*/
+#[derive(Deserialize)]
+pub struct SummaryDate
+{
+    /// UTC day, like "2020-09-13"
+    pub date: Option<String>
+}
+
+/**
+Query string accepted by the session export endpoint. Both ends of the range are UTC days, like "2020-09-13", matched against session end times.
+*/
+#[derive(Deserialize)]
+pub struct ExportRange
+{
+    pub from: Option<String>,
+    pub to: Option<String>,
+    #[serde(default)]
+    pub format: ExportFormat
+}
 
 /**
 Responds to requests for the api endpoint metrics
@@ -15,18 +197,53 @@ HttpResponse containing (if successful) JSON with the requested data.
 */
 pub async fn metrics() -> HttpResponse
 {
-    match METRICS.lock()
+    ResponseBuilder::new(StatusCode::OK).set_header(header::CONTENT_TYPE, "application/json; charset=utf-8").json(METRICS.snapshot())
+}
+
+/**
+Responds to scrapes from Prometheus.
+
+# Returns
+HttpResponse containing (if successful) the current metrics in the Prometheus text exposition format.
+*/
+pub async fn prometheus_metrics() -> HttpResponse
+{
+    ResponseBuilder::new(StatusCode::OK).set_header(header::CONTENT_TYPE, "text/plain; version=0.0.4; charset=utf-8").body(prometheus::render(&METRICS.tally()))
+}
+
+/**
+Responds to requests for the api endpoint metrics/stream, which keeps the connection open as a server-sent event stream.
+
+The first event, named "snapshot", carries the same JSON as the metrics endpoint.
+After that, unnamed events carry one watcher join/update/leave each, to be applied on top of the snapshot.
+
+# Returns
+HttpResponse streaming `text/event-stream` until the client disconnects.
+*/
+pub async fn metrics_stream() -> HttpResponse
+{
+    // The events since the snapshot was begun are sent on after it, so none can slip in between the snapshot and the subscription
+    let (since, snapshot) = METRICS.snapshot_since();
+    let rx = match serde_json::to_string(&snapshot)
     {
-        Ok(g)  => ResponseBuilder::new(StatusCode::OK                 ).set_header(header::CONTENT_TYPE, "application/json; charset=utf-8").json(&*g),
-        Err(_) => ResponseBuilder::new(StatusCode::SERVICE_UNAVAILABLE).set_header(header::CONTENT_TYPE, "application/json; charset=utf-8").json(0)
-    }
+        Ok(json) => events::subscribe(&json, since),
+        Err(_) => {return ResponseBuilder::new(StatusCode::INTERNAL_SERVER_ERROR).body("");}
+    };
+
+    ResponseBuilder::new(StatusCode::OK)
+        .set_header(header::CONTENT_TYPE, "text/event-stream")
+        .set_header(header::CACHE_CONTROL, "no-cache")
+        .streaming(rx.map(Ok::<_, actix_web::Error>))
 }
 
 /**
-Responds to requests for the api endpoint "watcher"
+Responds to requests for the api endpoint "videos/{id}/watcher"
 
 # Parameters
-- `watcher_data`: actix-generated tuple containing the captured parameters "vid" (video id) and "pct" (percent completion)
+- `watcher_data`: actix-generated tuple containing the captured parameters "id" (video id) and "pct" (percent completion)
+- `query`: actix-generated struct containing the query parameters "token" (playback token), "session" (session ID, if tokens aren't required)
+  and "event" (what just happened in the player, if anything)
+- `body`: empty, or JSON saying how playback is going, from players that measure it: see `quality::Quality`
 
 # Returns
 HttpResponse with a blank body.
@@ -34,27 +251,654 @@ HttpResponse with a blank body.
 # Errors
 The HTTP status code can indicate failure, which happens when anything goes wrong like invalid input.
 */
-pub async fn watcher(req: HttpRequest, watcher_data: web::Path<(usize, u8)>) -> HttpResponse
+pub async fn watcher(req: HttpRequest, watcher_data: web::Path<(String, u8)>, query: web::Query<WatcherSession>, body: web::Bytes) -> HttpResponse
+{
+    let (video_id, percent) = watcher_data.into_inner();
+    report_watcher(&req, &video_id, percent, &query, &body)
+}
+
+/**
+Responds to requests for the deprecated api endpoint "watcher", which identifies the video by its position in the catalog.
+Use "videos/{id}/watcher" instead, since positions change when the catalog is reordered.
+
+# Parameters
+- `watcher_data`: actix-generated tuple containing the captured parameters "vid" (video index) and "pct" (percent completion)
+- `query`: actix-generated struct containing the query parameters "token" (playback token), "session" (session ID, if tokens aren't required)
+  and "event" (what just happened in the player, if anything)
+
+# Returns
+HttpResponse with a blank body, and a `Deprecation` header.
+
+# Errors
+The HTTP status code can indicate failure, which happens when anything goes wrong like invalid input.
+*/
+pub async fn watcher_by_index(req: HttpRequest, watcher_data: web::Path<(usize, u8)>, query: web::Query<WatcherSession>) -> HttpResponse
 {
     let (video_index, percent) = (watcher_data.0, watcher_data.1);
+    let mut response = match video_id_at(video_index)
+    {
+        Some(id) => report_watcher(&req, &id, percent, &query, &[]),
+        None => {
+            REPORTS.received();
+            REPORTS.rejected(Rejection::InvalidVideo);
+            ResponseBuilder::new(StatusCode::NOT_FOUND).body("")
+        }
+    };
+    deprecated(&mut response);
+    response
+}
+
+/**
+Record one progress report, for the HTTP watcher endpoints.
+*/
+fn report_watcher(req: &HttpRequest, video_id: &str, percent: u8, query: &WatcherSession, body: &[u8]) -> HttpResponse
+{
+    REPORTS.received();
 
-    let ip: IpAddr = match req.peer_addr()
+    let quality = if body.is_empty()
     {
-        Some(a) => a.ip(),
+        None
+    }else{
+        match serde_json::from_slice::<Quality>(body)
+        {
+            Ok(q) => Some(q),
+            Err(e) => {
+                warn!("Rejected report with unreadable quality: {}", e);
+                REPORTS.rejected(Rejection::Malformed);
+                return ResponseBuilder::new(StatusCode::BAD_REQUEST).body("");
+            }
+        }
+    };
+
+    let ip: IpAddr = match net::client_ip(req, &SETTINGS.get().network.trusted_proxies)
+    {
+        Some(a) => a,
         None => {
             warn!("Got API request without a source address, discarding");
             return ResponseBuilder::new(StatusCode::FAILED_DEPENDENCY).body("");
         }
     };
 
-    match METRICS.try_lock()
+    let session = match report_session(req, query, ip)
+    {
+        Ok(s) => s,
+        Err(response) => return response
+    };
+
+    let report = WatcherReport{percent: Some(percent), event: query.event, quality, ..WatcherReport::default()};
+    let status = match METRICS.report(&session, ip, video_id, &report, &SETTINGS.get().reports)
+    {
+        Ok(()) => StatusCode::OK,
+        Err(r) => rejection_status(r)
+    };
+    ResponseBuilder::new(status)
+        .body("")
+}
+
+/**
+Responds to requests for the api endpoint "v2/watcher", which takes a report as a JSON body instead of in the path.
+
+# Parameters
+- `query`: actix-generated struct containing the query parameters "token" (playback token) and "session" (session ID, if tokens aren't required).
+  Any "event" here is ignored in favor of the one in the body.
+- `body`: JSON as described by `WatcherReportBody`
+
+# Returns
+HttpResponse with a blank body.
+
+# Errors
+JSON listing what was wrong, like `{"errors": [{"field": "position", "message": "must not be negative"}]}`,
+with status 400 for an unreadable or invalid body, 403 for a missing or bad playback token, 404 for an unknown video, or 429 for reporting too often.
+*/
+pub async fn watcher_v2(req: HttpRequest, query: web::Query<WatcherSession>, body: web::Bytes) -> HttpResponse
+{
+    REPORTS.received();
+
+    let body: WatcherReportBody = match serde_json::from_slice(&body)
+    {
+        Ok(b) => b,
+        Err(e) => {
+            warn!("Rejected unreadable watcher report: {}", e);
+            REPORTS.rejected(Rejection::Malformed);
+            return report_errors(StatusCode::BAD_REQUEST, vec![FieldError{field: None, message: e.to_string()}]);
+        }
+    };
+    let catalog_duration = match SETTINGS.get().media.videos.iter().find(|v| v.id == body.video)
+    {
+        Some(v) => v.duration,
+        None => {
+            REPORTS.rejected(Rejection::InvalidVideo);
+            return report_errors(StatusCode::NOT_FOUND, vec![rejection_error(Rejection::InvalidVideo)]);
+        }
+    };
+    let video_id = body.video.clone();
+    let report = match body.validate(catalog_duration)
+    {
+        Ok(r) => r,
+        Err(errors) => {
+            warn!("Rejected invalid watcher report with {} problem(s)", errors.len());
+            REPORTS.rejected(Rejection::Malformed);
+            return report_errors(StatusCode::BAD_REQUEST, errors);
+        }
+    };
+
+    let ip: IpAddr = match net::client_ip(&req, &SETTINGS.get().network.trusted_proxies)
+    {
+        Some(a) => a,
+        None => {
+            warn!("Got API request without a source address, discarding");
+            return report_errors(StatusCode::FAILED_DEPENDENCY, vec![FieldError{field: None, message: String::from("couldn't tell where the report came from")}]);
+        }
+    };
+    let session = match report_session(&req, &query, ip)
+    {
+        Ok(s) => s,
+        Err(response) if response.status() == StatusCode::FORBIDDEN => {
+            return report_errors(StatusCode::FORBIDDEN, vec![FieldError{field: None, message: String::from("missing, invalid or expired playback token")}]);
+        },
+        Err(response) => {
+            return report_errors(response.status(), vec![FieldError{field: None, message: String::from("malformed session ID")}]);
+        }
+    };
+
+    match METRICS.report(&session, ip, &video_id, &report, &SETTINGS.get().reports)
+    {
+        Ok(()) => ResponseBuilder::new(StatusCode::OK).body(""),
+        Err(r) => report_errors(rejection_status(r), vec![rejection_error(r)])
+    }
+}
+
+/// The status to turn a watcher report away with, for a reason the live metrics gave.
+fn rejection_status(reason: Rejection) -> StatusCode
+{
+    match reason
+    {
+        Rejection::InvalidVideo => StatusCode::NOT_FOUND,
+        Rejection::BadToken => StatusCode::FORBIDDEN,
+        Rejection::TooFrequent => StatusCode::TOO_MANY_REQUESTS,
+        _ => StatusCode::BAD_REQUEST
+    }
+}
+
+/// What to tell a v2 player about why the live metrics turned its report away.
+fn rejection_error(reason: Rejection) -> FieldError
+{
+    match reason
+    {
+        Rejection::InvalidVideo => FieldError::new("video", "no such video"),
+        Rejection::BadToken => FieldError{field: None, message: String::from("missing, invalid or expired playback token")},
+        Rejection::OutOfRange => FieldError::new("position", "is past the end of the video"),
+        Rejection::TooFrequent => FieldError{field: None, message: String::from("too soon after the last report")},
+        Rejection::Malformed => FieldError{field: None, message: String::from("not something a real player would send")}
+    }
+}
+
+/// Respond to a v2 watcher report with what was wrong with it.
+fn report_errors(status: StatusCode, errors: Vec<FieldError>) -> HttpResponse
+{
+    ResponseBuilder::new(status)
+        .set_header(header::CONTENT_TYPE, "application/json; charset=utf-8")
+        .json(serde_json::json!({"errors": errors}))
+}
+
+/**
+Responds to requests for the api endpoint metrics/timeseries
+
+# Parameters
+- `query`: actix-generated struct containing the optional query parameters "video" (video id, or all the videos together if left out),
+  "from" and "to" (unix timestamps) and "step" (seconds per point).
+  `to` defaults to now, `from` to an hour before `to`, and `step` to the finest resolution kept that far back:
+  a second for the last hour, a minute for the last day, and an hour for the last 30 days.
+
+# Returns
+HttpResponse containing (if successful) JSON with the most and mean watchers over each step of the range. See `timeseries::TimeseriesReport`.
+
+# Errors
+Status 400 with a JSON error message for a range or step that doesn't make sense, or 404 for a video that has never been sampled.
+*/
+pub async fn timeseries(query: web::Query<TimeseriesQuery>) -> HttpResponse
+{
+    match timeseries::lock(&TIMESERIES).query(&query, Utc::now().timestamp())
+    {
+        Ok(Some(report)) => ResponseBuilder::new(StatusCode::OK).set_header(header::CONTENT_TYPE, "application/json; charset=utf-8").json(report),
+        Ok(None) => ResponseBuilder::new(StatusCode::NOT_FOUND).body(""),
+        Err(e) => ResponseBuilder::new(StatusCode::BAD_REQUEST).set_header(header::CONTENT_TYPE, "application/json; charset=utf-8").json(serde_json::json!({"error": e}))
+    }
+}
+
+/**
+Responds to requests for the api endpoint "videos/{id}/retention"
+
+# Parameters
+- `video_id`: actix-generated struct containing the captured parameter "id" (video id)
+
+# Returns
+HttpResponse containing (if successful) JSON with how many watchers of the video were still watching at each percent of it,
+counting the sessions that have ended since the app started. See `retention::RetentionCurve`.
+
+# Errors
+Status 404 if there's no such video in the catalog.
+*/
+pub async fn retention(video_id: web::Path<String>) -> HttpResponse
+{
+    match METRICS.retention(&video_id)
+    {
+        Some(curve) => ResponseBuilder::new(StatusCode::OK).set_header(header::CONTENT_TYPE, "application/json; charset=utf-8").json(curve),
+        None => ResponseBuilder::new(StatusCode::NOT_FOUND).body("")
+    }
+}
+
+/**
+Responds to requests for the api endpoint "videos/{id}/heatmap"
+
+# Parameters
+- `video_id`: actix-generated struct containing the captured parameter "id" (video id)
+
+# Returns
+HttpResponse containing (if successful) JSON with how many times each second of the video was played, played again and skipped,
+counting every report since the app started. See `heatmap::HeatmapReport`.
+
+# Errors
+Status 404 if there's no such video in the catalog.
+*/
+pub async fn heatmap(video_id: web::Path<String>) -> HttpResponse
+{
+    match METRICS.heatmap(&video_id)
+    {
+        Some(heatmap) => ResponseBuilder::new(StatusCode::OK).set_header(header::CONTENT_TYPE, "application/json; charset=utf-8").json(heatmap),
+        None => ResponseBuilder::new(StatusCode::NOT_FOUND).body("")
+    }
+}
+
+/**
+Responds to requests for the api endpoint summary
+
+# Parameters
+- `query`: actix-generated struct containing the optional query parameter "date", the UTC day to sum up, like "2020-09-13". Defaults to today.
+
+# Returns
+HttpResponse containing (if successful) JSON with the day's sessions, unique viewers, watch time, completion rate and peak viewers,
+for all the videos together and for each one. See `summary::DailySummary`.
+
+# Errors
+Status 400 for a date that can't be read, or 500 if the history or peaks can't be.
+*/
+pub async fn summary(query: web::Query<SummaryDate>) -> HttpResponse
+{
+    let date = match &query.date
+    {
+        Some(d) => match NaiveDate::parse_from_str(d, "%Y-%m-%d")
+        {
+            Ok(date) => date,
+            Err(_) => return ResponseBuilder::new(StatusCode::BAD_REQUEST).set_header(header::CONTENT_TYPE, "application/json; charset=utf-8")
+                .json(serde_json::json!({"error": "date must be a day like 2020-09-13"}))
+        },
+        None => Utc::now().naive_utc().date()
+    };
+
+    //reading the history is blocking disk I/O, so it's kept off the worker thread
+    match web::block(move || summary::daily(date)).await
+    {
+        Ok(s) => ResponseBuilder::new(StatusCode::OK).set_header(header::CONTENT_TYPE, "application/json; charset=utf-8").json(s),
+        Err(e) => {
+            error!("Couldn't sum up {}: {}", date, e);
+            ResponseBuilder::new(StatusCode::INTERNAL_SERVER_ERROR).set_header(header::CONTENT_TYPE, "application/json; charset=utf-8").json(0)
+        }
+    }
+}
+
+/**
+Responds to requests for the api endpoint history
+
+# Parameters
+- `range`: actix-generated struct containing the optional query parameters "from" and "to".
+  `to` defaults to now and `from` defaults to 24 hours before `to`.
+
+# Returns
+HttpResponse containing (if successful) a JSON array of the viewing sessions that ended within the range.
+*/
+pub async fn history(range: web::Query<HistoryRange>) -> HttpResponse
+{
+    let to = range.to.unwrap_or_else(|| Utc::now().timestamp());
+    let from = range.from.unwrap_or_else(|| to.saturating_sub(86400));
+
+    //reading the history is blocking disk I/O, so it's kept off the worker thread
+    match web::block(move || history::query(from, to)).await
+    {
+        Ok(sessions) => ResponseBuilder::new(StatusCode::OK).set_header(header::CONTENT_TYPE, "application/json; charset=utf-8").json(sessions),
+        Err(e) => {
+            error!("Couldn't read history: {}", e);
+            ResponseBuilder::new(StatusCode::INTERNAL_SERVER_ERROR).set_header(header::CONTENT_TYPE, "application/json; charset=utf-8").json(0)
+        }
+    }
+}
+
+/**
+Responds to requests for the api endpoint export/sessions, streaming the viewing sessions in the history for spreadsheets and the like.
+
+The response is sent a batch of sessions at a time as the history is read, line by line, so neither the range nor any one day has to fit in memory.
+Each session is a row with the columns in `export::COLUMNS`, where the viewer is an id standing in for their address.
+
+# Parameters
+- `range`: actix-generated struct containing the optional query parameters "from" and "to", UTC days like "2020-09-13", including both.
+  `to` defaults to today and `from` to the same day as `to`. The optional "format" is "csv" (the default) or "ndjson".
+
+# Returns
+HttpResponse streaming the sessions, named as a download like `sessions-2020-09-01-2020-09-13.csv`.
+
+# Errors
+Status 400 with a JSON error message for days that can't be read or are the wrong way round, or 500 if the history folder can't be read.
+If a day of history can't be read partway through, the response is cut off there.
+*/
+pub async fn export_sessions(range: web::Query<ExportRange>) -> HttpResponse
+{
+    let bad_request = |message: &str| ResponseBuilder::new(StatusCode::BAD_REQUEST).set_header(header::CONTENT_TYPE, "application/json; charset=utf-8")
+        .json(serde_json::json!({"error": message}));
+    let day = |d: &Option<String>| d.as_deref().map(|d| NaiveDate::parse_from_str(d, "%Y-%m-%d")).transpose();
+    let (from, to) = match (day(&range.from), day(&range.to))
     {
-        Ok(mut g) => {
-            g.report(ip, video_index, percent);
+        (Ok(from), Ok(to)) => {
+            let to = to.unwrap_or_else(|| Utc::now().naive_utc().date());
+            (from.unwrap_or(to), to)
         },
-        Err(_) => {return ResponseBuilder::new(StatusCode::SERVICE_UNAVAILABLE).body("");}
+        _ => return bad_request("from and to must be days like 2020-09-13")
     };
-    
+    if from > to
+    {
+        return bad_request("from must not be after to");
+    }
+
+    let days = match web::block(move || history::days(from, to)).await
+    {
+        Ok(days) => days,
+        Err(e) => {
+            error!("Couldn't read history to export: {}", e);
+            return ResponseBuilder::new(StatusCode::INTERNAL_SERVER_ERROR).body("");
+        }
+    };
+
+    let format = range.format;
+    let titles: Arc<HashMap<String, String>> = Arc::new(SETTINGS.get().media.videos.iter().map(|v| (v.id.clone(), v.title.clone())).collect());
+    let header = futures::stream::iter(format.header().map(|h| Ok(Bytes::from(h))));
+    let rows = futures::stream::unfold(Some(history::SessionReader::new(days)), move |reader| {
+        let titles = Arc::clone(&titles);
+        async move {
+            let mut reader = reader?;
+            match web::block(move || reader.next_batch(EXPORT_BATCH).map(|batch| batch.map(|b| (reader, b)))).await
+            {
+                Ok(Some((reader, batch))) => Some((Ok(Bytes::from(format.rows(&batch, &titles))), Some(reader))),
+                Ok(None) => None,
+                Err(e) => {
+                    error!("Couldn't read history while exporting, cutting the export off: {}", e);
+                    Some((Err(actix_web::Error::from(e)), None))
+                }
+            }
+        }
+    });
+    let body = header.chain(rows);
+
     ResponseBuilder::new(StatusCode::OK)
-        .body("")
-}
\ No newline at end of file
+        .set_header(header::CONTENT_TYPE, format.content_type())
+        .set_header(header::CONTENT_DISPOSITION, format!("attachment; filename=\"sessions-{}-{}.{}\"", from, to, format.extension()))
+        .streaming(body)
+}
+
+/**
+Responds to requests for the api endpoint "videos/{id}/watcher" without a percent, by upgrading the connection to a WebSocket.
+
+Once connected, the player sends a text message each time it wants to report progress,
+so one connection replaces the repeated POSTs to the "watcher" endpoint. The message is a JSON object with the same fields
+as the body taken by the "v2/watcher" endpoint, except for the video, like
+`{"position": 42.5, "duration": 95.0, "event": "pause", "quality": {"rebuffers": 1}}`.
+Older players send `"percent"` instead of the position and duration, or just the percent on its own.
+When the connection closes, the watcher is removed immediately and their session goes to the history,
+instead of waiting for the updater to notice they've gone quiet.
+
+# Parameters
+- `video_id`: actix-generated struct containing the captured parameter "id" (video id)
+- `query`: actix-generated struct containing the query parameters "token" (playback token) and "session" (session ID, if tokens aren't required)
+- `payload`: the incoming byte stream, which carries the client's WebSocket frames after the handshake
+
+# Returns
+HttpResponse switching protocols, whose body carries our frames for as long as the connection lasts.
+
+# Errors
+The HTTP status code can indicate failure, which happens when the request isn't a valid WebSocket handshake.
+*/
+pub async fn watcher_socket(req: HttpRequest, video_id: web::Path<String>, query: web::Query<WatcherSession>, payload: web::Payload) -> HttpResponse
+{
+    open_watcher_socket(&req, video_id.into_inner(), &query, payload)
+}
+
+/**
+Responds to requests for the deprecated WebSocket form of the api endpoint "watcher", which identifies the video by its position in the catalog.
+Use "videos/{id}/watcher" instead, since positions change when the catalog is reordered.
+
+# Parameters
+- `video_index`: actix-generated struct containing the captured parameter "vid" (video index)
+- `query`: actix-generated struct containing the query parameters "token" (playback token) and "session" (session ID, if tokens aren't required)
+- `payload`: the incoming byte stream, which carries the client's WebSocket frames after the handshake
+
+# Returns
+HttpResponse switching protocols, with a `Deprecation` header.
+
+# Errors
+The HTTP status code can indicate failure, which happens when the request isn't a valid WebSocket handshake or the video doesn't exist.
+*/
+pub async fn watcher_socket_by_index(req: HttpRequest, video_index: web::Path<usize>, query: web::Query<WatcherSession>, payload: web::Payload) -> HttpResponse
+{
+    let mut response = match video_id_at(video_index.into_inner())
+    {
+        Some(id) => open_watcher_socket(&req, id, &query, payload),
+        None => ResponseBuilder::new(StatusCode::NOT_FOUND).body("")
+    };
+    deprecated(&mut response);
+    response
+}
+
+/**
+Complete the WebSocket handshake for a watcher and start reading what they send, for the WebSocket watcher endpoints.
+*/
+fn open_watcher_socket(req: &HttpRequest, video_id: String, query: &WatcherSession, payload: web::Payload) -> HttpResponse
+{
+    let ip: IpAddr = match net::client_ip(req, &SETTINGS.get().network.trusted_proxies)
+    {
+        Some(a) => a,
+        None => {
+            warn!("Got API request without a source address, discarding");
+            return ResponseBuilder::new(StatusCode::FAILED_DEPENDENCY).body("");
+        }
+    };
+
+    let session = match report_session(req, query, ip)
+    {
+        Ok(s) => s,
+        Err(response) => {
+            REPORTS.received();
+            return response;
+        }
+    };
+
+    let mut response = match ws::handshake(req.head())
+    {
+        Ok(r) => r,
+        Err(e) => {return e.error_response();}
+    };
+
+    let (tx, rx) = unbounded::<Bytes>();
+    actix_rt::spawn(watcher_socket_session(payload, tx, session, ip, video_id));
+
+    response.streaming(rx.map(Ok::<_, actix_web::Error>))
+}
+
+/**
+Read the frames of one watcher's WebSocket until it closes, reporting each progress message, then end their session.
+
+# Parameters
+- `payload`: raw bytes coming from the client
+- `tx`: where to put the raw bytes of frames we send back to the client
+- `session`: session ID of the watcher
+- `ip`: address of the watcher
+- `video_id`: catalog id of the video they are watching
+*/
+async fn watcher_socket_session(mut payload: web::Payload, tx: UnboundedSender<Bytes>, session: String, ip: IpAddr, video_id: String)
+{
+    let mut codec = ws::Codec::new();
+    let mut buf = BytesMut::new();
+    let send = |codec: &mut ws::Codec, msg: ws::Message| {
+        let mut out = BytesMut::new();
+        if codec.encode(msg, &mut out).is_ok()
+        {
+            let _ = tx.unbounded_send(out.freeze());
+        }
+    };
+
+    'conn: while let Some(chunk) = payload.next().await
+    {
+        match chunk
+        {
+            Ok(bytes) => buf.extend_from_slice(&bytes),
+            Err(_) => break
+        }
+        loop
+        {
+            let frame = match codec.decode(&mut buf)
+            {
+                Ok(Some(f)) => f,
+                Ok(None) => break,
+                Err(e) => {
+                    warn!("Dropping watcher socket after protocol error: {}", e);
+                    break 'conn;
+                }
+            };
+            match frame
+            {
+                ws::Frame::Text(text) => {
+                    REPORTS.received();
+                    match std::str::from_utf8(&text).ok().and_then(socket_report)
+                    {
+                        Some(report) => {
+                            //rejections are counted and logged as they happen, and the player has no use for hearing about them
+                            let _ = METRICS.report(&session, ip, &video_id, &report, &SETTINGS.get().reports);
+                        },
+                        None => {
+                            warn!("Ignoring unreadable watcher socket message");
+                            REPORTS.rejected(Rejection::Malformed);
+                        }
+                    }
+                },
+                ws::Frame::Ping(msg) => send(&mut codec, ws::Message::Pong(msg)),
+                ws::Frame::Close(reason) => {
+                    send(&mut codec, ws::Message::Close(reason));
+                    break 'conn;
+                },
+                _ => {}
+            }
+        }
+    }
+    tx.close_channel();
+
+    if let Some(session) = METRICS.depart(&session, &video_id)
+    {
+        //writing the history is blocking disk I/O, so it's kept off the worker thread
+        if let Err(e) = web::block(move || history::record(&[session])).await
+        {
+            error!("Couldn't record session to history: {}", e);
+        }
+    }
+}
+
+/**
+Read a message from a watcher's WebSocket: either just the percent, or a JSON object with the position or percent, an event and so on.
+*/
+fn socket_report(text: &str) -> Option<WatcherReport>
+{
+    let text = text.trim();
+    match text.parse::<u8>()
+    {
+        Ok(percent) => Some(WatcherReport::new(percent, PlayerEvent::Progress)),
+        Err(_) => serde_json::from_str(text).ok()
+    }
+}
+
+/**
+Work out which session a watcher report belongs to, from its playback token.
+While tokens aren't required, reports without one fall back to the session ID the player chose.
+
+# Parameters
+- `req`: the request, for its cookies
+- `query`: the token and session ID from the query string
+- `ip`: address the request came from
+
+# Returns
+The session ID.
+
+# Errors
+The response to turn the report away with: 403 for a missing, forged or expired token, or 400 for a malformed session ID.
+A bad token is counted and logged as a rejected report.
+*/
+fn report_session(req: &HttpRequest, query: &WatcherSession, ip: IpAddr) -> Result<String, HttpResponse>
+{
+    let forbidden = |why: &str| {
+        warn!("Rejected report from {}: {} playback token", ip, why);
+        REPORTS.rejected(Rejection::BadToken);
+        ResponseBuilder::new(StatusCode::FORBIDDEN).body("")
+    };
+    match &query.token
+    {
+        Some(token) => auth::playback_session(token).ok_or_else(|| forbidden("invalid or expired")),
+        None if SETTINGS.get().reports.require_token => Err(forbidden("no")),
+        None => session_id(req, &query.session, ip).ok_or_else(|| ResponseBuilder::new(StatusCode::BAD_REQUEST).body(""))
+    }
+}
+
+/**
+Work out which session a watcher report belongs to, for players that don't use playback tokens.
+
+The client can give its session ID in the query string or in a cookie. Clients that give neither,
+such as older players, are identified by their address as before.
+
+# Parameters
+- `req`: the request, for its cookies
+- `given`: session ID from the query string, if any
+- `ip`: address the request came from
+
+# Returns
+The session ID, or None if the client gave one that isn't acceptable (empty, too long, or containing anything but letters, digits, `-` and `_`).
+*/
+fn session_id(req: &HttpRequest, given: &Option<String>, ip: IpAddr) -> Option<String>
+{
+    let given = match given
+    {
+        Some(s) => Some(s.clone()),
+        None => req.cookie(SESSION_COOKIE).map(|c| c.value().to_owned())
+    };
+    match given
+    {
+        Some(s) => {
+            if s.is_empty() || s.len() > 64 || !s.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+            {
+                warn!("Rejecting watcher report with malformed session ID");
+                return None;
+            }
+            Some(s)
+        },
+        None => Some(ip.to_string())
+    }
+}
+
+/**
+Look up the catalog id of the video at a position in the catalog, for the deprecated endpoints that still address videos that way.
+*/
+fn video_id_at(index: usize) -> Option<String>
+{
+    SETTINGS.get().media.videos.get(index).map(|v| v.id.clone())
+}
+
+/**
+Mark a response as coming from a deprecated endpoint.
+*/
+fn deprecated(response: &mut HttpResponse)
+{
+    response.headers_mut().insert(header::HeaderName::from_static("deprecation"), header::HeaderValue::from_static("true"));
+}
@@ -1,8 +1,30 @@
+pub mod admin;
 pub mod api;
 pub mod pages;
 
 use crate::settings::SETTINGS;
 
+/**
+Escape text so it can be placed in HTML content or a quoted attribute without being interpreted as markup.
+*/
+fn html_escape(text: &str) -> String
+{
+    let mut out = String::with_capacity(text.len());
+    for c in text.chars()
+    {
+        match c
+        {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            _ => out.push(c)
+        }
+    }
+    out
+}
+
 fn page_header(menu: &str) -> String
 {
     format!("<div><a href='/'>Home</a> <span>{}</span> <a href='/dashboard'>Dashboard</a></div>", menu)
@@ -32,7 +54,7 @@ fn html_construct(title: &str, head_extra: &str, body: &str) -> String
   <script src='https://unpkg.com/jquery@3.5.1/dist/jquery.min.js'></script>
   <script src='https://unpkg.com/moment@2.19.3/min/moment-with-locales.min.js'></script>
   <link rel='stylesheet' href='static/main.css'/>
-  <script>const videos = [\"{}\"];</script>
+  <script>const videos = {};</script>
   {}
   <title>{}</title>
  </head>
@@ -40,5 +62,13 @@ fn html_construct(title: &str, head_extra: &str, body: &str) -> String
  {}
  </body>
 </html>",
-    &SETTINGS.media.videos.join("\",\""), head_extra, title, body)
+    videos_json(), head_extra, title, body)
+}
+
+/**
+The video catalog as JSON, safe to embed in a script tag.
+*/
+fn videos_json() -> String
+{
+    serde_json::to_string(&SETTINGS.get().media.videos).unwrap_or_else(|_| String::from("[]")).replace("</", "<\\/")
 }
@@ -1,15 +1,18 @@
-use std::path::Path;
-
-use actix_web::{HttpResponse, http::header, http::StatusCode};
+use actix_web::{web, HttpRequest, HttpResponse, http::header, http::HeaderValue, http::StatusCode};
 use actix_http::ResponseBuilder;
-/*use log::{error, warn, info, debug, trace, log, Level};*/
+use log::{error, warn, info, /*debug, trace, log, Level*/};
+
+use std::sync::Arc;
 
+use crate::auth::{self, Identity, LOGIN_COOKIE};
+use crate::ratelimit::Limiter;
 use crate::settings::SETTINGS;
 use super::html_construct;
+use super::html_escape;
 use super::page_header;
 
 /**
-Responds to requests for the main page at the domain root.
+Responds to requests for the main page at the domain root, handing the player a new playback token.
 
 # Returns
 HttpResponse containing the main page
@@ -17,36 +20,50 @@ HttpResponse containing the main page
 pub async fn index() -> HttpResponse
 {
     let mut video_links = String::new();
-    for index in 0..SETTINGS.media.videos.len()
+    for video in &SETTINGS.get().media.videos
     {
-        let vid_name = match Path::new(&SETTINGS.media.videos[index]).file_name()
-        {
-            Some(s) => s.to_string_lossy().into_owned(),
-            None => format!("Video {}",index)
-        };
-        let link = format!("<a onclick='switchVid({})'>{}</a>", index, vid_name);
+        let link = format!("<a onclick='switchVid(\"{}\")' title='{}'>{}</a>", video.id, html_escape(&video.description), html_escape(&video.title));
         video_links.push_str(&link);
     }
     
-    let body = format!("{}<video id='player' controls disablePictureInPicture />", page_header(&video_links));
-    let head = "<script src='static/video.js'></script>";
+    let body = format!("{}<video id='player' controls disablePictureInPicture></video><p id='description'></p>", page_header(&video_links));
+    //each page load is one playback, with its own token for the player to report with
+    let head = format!("<script>const playbackToken = \"{}\";</script><script src='static/video.js'></script>", auth::playback_token());
 
-    let html = html_construct("Home - Metric Videos", head, &body);
+    let html = html_construct("Home - Metric Videos", &head, &body);
 
     ResponseBuilder::new(StatusCode::OK)
         .set_header(header::CONTENT_TYPE, "text/html; charset=utf-8")
+        .set_header(header::CACHE_CONTROL, "no-store")
         .body(html)
 }
 
+/**
+Query string and form fields accepted by the login page.
+*/
+#[derive(Deserialize)]
+pub struct LoginForm
+{
+    pub name: Option<String>,
+    pub password: Option<String>,
+    /// Where to go once logged in
+    pub next: Option<String>
+}
+
 /**
 Responds to requests for the metrics dashboard.
 
 # Returns
 HttpResponse containing the dashboard page
 */
-pub async fn dashboard() -> HttpResponse
+pub async fn dashboard(req: HttpRequest) -> HttpResponse
 {
-    let body = format!("{}<div id='dashboard'></div><script src='/static/startReact.js'></script>", page_header(""));
+    let logout = match req.extensions().get::<Identity>()
+    {
+        Some(identity) => format!("<form method='post' action='/logout'><button>Log out {}</button></form>", html_escape(&identity.name)),
+        None => String::new()
+    };
+    let body = format!("{}{}<div id='dashboard'></div><script src='/static/startReact.js'></script>", page_header(""), logout);
     let head = "
     <script src='https://unpkg.com/react@16/umd/react.production.min.js'></script>
     <script src='https://unpkg.com/react-dom@16/umd/react-dom.production.min.js'></script>
@@ -61,6 +78,106 @@ pub async fn dashboard() -> HttpResponse
         .body(html)
 }
 
+/**
+Responds to requests for the login page.
+
+# Parameters
+- `form`: actix-generated struct parsed from the query string, of which only "next" is used
+
+# Returns
+HttpResponse containing the login page
+*/
+pub async fn login(form: web::Query<LoginForm>) -> HttpResponse
+{
+    login_page(StatusCode::OK, form.next.as_deref(), "")
+}
+
+/**
+Responds to the login form being submitted. On success, the user gets a login cookie and is sent on to wherever they were headed.
+Tries at each user name are rate limited along with tries from each address, so guessing at one account from many addresses is slowed down too.
+
+# Parameters
+- `form`: actix-generated struct parsed from the form fields
+
+# Returns
+HttpResponse redirecting onward, or the login page again with HTTP 401 if the name or password is wrong,
+or with HTTP 429 and a `Retry-After` header if the name has been tried too often.
+*/
+pub async fn login_submit(req: HttpRequest, form: web::Form<LoginForm>) -> HttpResponse
+{
+    let settings = SETTINGS.get();
+    let name = form.name.clone().unwrap_or_default();
+    if let Err(retry_after) = Limiter::Login.take(&format!("user {}", name))
+    {
+        warn!("Rate limiting logins as {:?} for {}s", name, retry_after);
+        let mut response = login_page(StatusCode::TOO_MANY_REQUESTS, form.next.as_deref(), "Too many tries. Wait a little and try again.");
+        response.headers_mut().insert(header::RETRY_AFTER, HeaderValue::from(retry_after));
+        return response;
+    }
+
+    //hashing the password takes a while, so it's kept off the worker thread
+    let checked = {
+        let (settings, name, password) = (Arc::clone(&settings), name.clone(), form.password.clone().unwrap_or_default());
+        web::block(move || Ok::<_, ()>(auth::check_password(&settings, &name, &password))).await
+    };
+    let identity = match checked
+    {
+        Ok(Some(i)) => i,
+        Ok(None) => {
+            warn!("Failed login as {:?}", name);
+            return login_page(StatusCode::UNAUTHORIZED, form.next.as_deref(), "Wrong name or password.");
+        },
+        Err(e) => {
+            error!("Couldn't check the password for {:?}: {}", name, e);
+            return login_page(StatusCode::INTERNAL_SERVER_ERROR, form.next.as_deref(), "Something went wrong. Try again.");
+        }
+    };
+    info!("{} logged in", identity.name);
+
+    let hours = settings.auth.session_hours;
+    let secure = if req.connection_info().scheme() == "https" {"; Secure"} else {""};
+    let cookie = format!("{}={}; Path=/; Max-Age={}; HttpOnly; SameSite=Lax{}", LOGIN_COOKIE, auth::login_cookie_value(&identity.name, hours), u64::from(hours) * 3600, secure);
+    ResponseBuilder::new(StatusCode::SEE_OTHER)
+        .set_header(header::SET_COOKIE, cookie)
+        .set_header(header::LOCATION, local_path(form.next.as_deref()).unwrap_or("/dashboard"))
+        .finish()
+}
+
+/**
+Responds to requests to log out, by clearing the login cookie.
+
+# Returns
+HttpResponse redirecting to the main page.
+*/
+pub async fn logout() -> HttpResponse
+{
+    ResponseBuilder::new(StatusCode::SEE_OTHER)
+        .set_header(header::SET_COOKIE, format!("{}=; Path=/; Max-Age=0; HttpOnly; SameSite=Lax", LOGIN_COOKIE))
+        .set_header(header::LOCATION, "/")
+        .finish()
+}
+
+fn login_page(status: StatusCode, next: Option<&str>, message: &str) -> HttpResponse
+{
+    let next = local_path(next).unwrap_or("/dashboard");
+    let body = format!("{}<form id='login' method='post' action='/login'><p>{}</p>\
+        <input type='hidden' name='next' value='{}'/>\
+        <label>Name <input name='name' autocomplete='username' autofocus/></label>\
+        <label>Password <input type='password' name='password' autocomplete='current-password'/></label>\
+        <button>Log in</button></form>", page_header(""), html_escape(message), html_escape(next));
+    let html = html_construct("Log in - Metric Videos", "", &body);
+
+    ResponseBuilder::new(status)
+        .set_header(header::CONTENT_TYPE, "text/html; charset=utf-8")
+        .body(html)
+}
+
+/// Only follow redirects to paths on this site, so the login page can't be used to send people elsewhere.
+fn local_path(next: Option<&str>) -> Option<&str>
+{
+    next.filter(|n| n.starts_with('/') && !n.starts_with("//") && !n.starts_with("/\\"))
+}
+
 /**
 Responds to requests that don't match anything we have.
 
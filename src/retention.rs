@@ -0,0 +1,168 @@
+/// How many points a retention curve has: one for each whole percent from 0 to 100.
+pub const POINTS: usize = 101;
+
+/**
+Which parts of a video one watcher has watched, in whole percent.
+*/
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Watched([u64; 2]);
+
+impl Watched
+{
+    /// Mark one percent as watched. Anything past 100 counts as 100.
+    pub fn mark(&mut self, percent: u8)
+    {
+        let p = usize::from(percent.min(100));
+        self.0[p / 64] |= 1 << (p % 64);
+    }
+
+    /**
+    Mark everything from one percent to another as watched, for a watcher who played on from one to the other.
+    Going backwards only marks where they ended up.
+    */
+    pub fn mark_range(&mut self, from: u8, to: u8)
+    {
+        for p in from.min(to)..=to
+        {
+            self.mark(p);
+        }
+    }
+
+    pub fn contains(&self, percent: u8) -> bool
+    {
+        let p = usize::from(percent.min(100));
+        self.0[p / 64] & (1 << (p % 64)) != 0
+    }
+
+    /// The furthest percent marked as watched, or None if nothing has been.
+    pub fn furthest(&self) -> Option<u8>
+    {
+        (0..=100).rev().find(|p| self.contains(*p))
+    }
+}
+
+/**
+Where the watchers of one video stopped watching, built up from their sessions as they end.
+*/
+#[derive(Clone, Debug)]
+pub struct Retention
+{
+    sessions: u64,
+    /// How many of those sessions got no further than each percent of the video
+    stopped: [u64; POINTS],
+    /// How many of those sessions played each percent of the video, including parts they came back to after skipping ahead
+    played: [u64; POINTS],
+    /// Length of the video in seconds, as the last player that said saw it
+    duration: Option<f64>
+}
+
+impl Default for Retention
+{
+    fn default() -> Self
+    {
+        Retention{sessions: 0, stopped: [0; POINTS], played: [0; POINTS], duration: None}
+    }
+}
+
+impl Retention
+{
+    /**
+    Count one ended session. It counts as having stopped at the furthest point it watched.
+
+    # Parameters
+    - `watched`: which parts of the video they watched
+    - `duration`: length of the video in seconds as their player saw it, if it said
+    */
+    pub fn add(&mut self, watched: &Watched, duration: Option<f64>)
+    {
+        self.sessions += 1;
+        self.stopped[usize::from(watched.furthest().unwrap_or(0))] += 1;
+        for (p, count) in self.played.iter_mut().enumerate()
+        {
+            *count += u64::from(watched.contains(p as u8));
+        }
+        if duration.is_some()
+        {
+            self.duration = duration;
+        }
+    }
+
+    /**
+    Turn the counts into a curve of how many watchers were still watching at each point of the video, which is to say they got at least that far.
+    The curve never goes up: a watcher who skipped part of the video still counts as watching it, since they hadn't left yet.
+
+    # Parameters
+    - `video`: catalog id of the video
+    - `duration`: length of the video in seconds according to the catalog. If it doesn't say, the players' is used.
+
+    # Examples
+    ```
+    use metric_videos::retention::{Retention, Watched};
+    let mut retention = Retention::default();
+    let (mut all, mut half, mut skipped) = (Watched::default(), Watched::default(), Watched::default());
+    all.mark_range(0, 100);
+    half.mark_range(0, 50);
+    skipped.mark_range(0, 10);
+    skipped.mark_range(90, 100);
+    retention.add(&all, None);
+    retention.add(&half, Some(40.0));
+    retention.add(&skipped, None);
+
+    let curve = retention.curve("intro", None);
+    assert_eq!((curve.sessions, curve.duration), (3, Some(40.0)));
+    assert_eq!((curve.points[50].watching, curve.points[50].played), (3, 2));
+    assert_eq!((curve.points[51].watching, curve.points[51].played), (2, 1));
+    assert_eq!((curve.points[100].watching, curve.points[100].share), (2, 2.0 / 3.0));
+    assert_eq!(curve.points[100].position, Some(40.0));
+    ```
+    */
+    pub fn curve(&self, video: &str, duration: Option<u32>) -> RetentionCurve
+    {
+        let duration = duration.map(f64::from).or(self.duration);
+        //everyone who stopped at or after a point was still watching at it
+        let mut watching = self.sessions;
+        let mut points = Vec::with_capacity(POINTS);
+        for p in 0..POINTS
+        {
+            points.push(RetentionPoint{
+                percent: p as u8,
+                position: duration.map(|d| d * p as f64 / 100.0),
+                watching,
+                share: if self.sessions == 0 {0.0} else {watching as f64 / self.sessions as f64},
+                played: self.played[p]
+            });
+            watching -= self.stopped[p];
+        }
+        RetentionCurve{video: video.to_owned(), sessions: self.sessions, duration, points}
+    }
+}
+
+/**
+How many watchers of a video were still watching at each point of it, as served by the retention endpoint.
+*/
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct RetentionCurve
+{
+    /// Catalog id of the video
+    pub video: String,
+    /// How many ended sessions the curve is made from
+    pub sessions: u64,
+    /// Length of the video in seconds, if known
+    pub duration: Option<f64>,
+    /// One point for each whole percent of the video
+    pub points: Vec<RetentionPoint>
+}
+
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct RetentionPoint
+{
+    pub percent: u8,
+    /// Seconds into the video, if its length is known
+    pub position: Option<f64>,
+    /// How many sessions were still watching at this point, having got at least this far
+    pub watching: u64,
+    /// The share of all the sessions still watching at this point, from 0 to 1
+    pub share: f64,
+    /// How many sessions played this point, leaving out those that skipped over it
+    pub played: u64
+}
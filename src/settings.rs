@@ -1,14 +1,25 @@
-use clap::{Arg, App};
+use chrono::NaiveDate;
+use clap::{Arg, App, ArgMatches, SubCommand};
 use config::{ConfigError, Config, File};
-use log::{error/*, warn, info, debug, trace, log, Level*/};
+use log::{error, warn/*, info, debug, trace, log, Level*/};
+use std::collections::HashSet;
 use std::env;
 use std::fs;
+use std::io;
 use std::path::Path;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::SystemTime;
+
+use crate::auth::Role;
+use crate::net::Cidr;
+
+/// Where the main config file is, relative to the working dir.
+const PATH_CONFIG: &str = "config/config.toml";
 
 /**
 The portion of the config needed immediately, before we can even do so much as display an error over HTTP.
 */
-#[derive(Deserialize)]
+#[derive(Deserialize, Serialize, Clone)]
 pub struct Startup
 {
     pub working_dir: String,
@@ -18,20 +29,410 @@ pub struct Startup
 /**
 The portion of the config needed for listing available content.
 */
-#[derive(Deserialize)]
+#[derive(Deserialize, Serialize, Clone)]
 pub struct Media
 {
-    pub videos: Vec<String>
+    pub videos: Vec<Video>
+}
+
+impl Media
+{
+    /**
+    Check the catalog for problems that would stop us from telling the videos apart or playing them.
+
+    # Errors
+    A description of the first problem found: an empty, malformed or duplicate id, or a video without any sources.
+    Ids may only contain letters, digits, `-` and `_`, since they appear in API paths.
+    */
+    pub fn validate(&self) -> Result<(), String>
+    {
+        let mut seen: HashSet<&str> = HashSet::new();
+        for video in &self.videos
+        {
+            if video.id.is_empty()
+            {
+                return Err(format!("Video \"{}\" has an empty id", video.title));
+            }
+            if !video.id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+            {
+                return Err(format!("Video id \"{}\" may only contain letters, digits, '-' and '_'", video.id));
+            }
+            if !seen.insert(&video.id)
+            {
+                return Err(format!("Video id \"{}\" is used more than once", video.id));
+            }
+            if video.sources.is_empty()
+            {
+                return Err(format!("Video \"{}\" has no sources", video.id));
+            }
+        }
+        Ok(())
+    }
+
+    /**
+    Write this catalog to the config file in place of the one there, leaving the rest of the file as it was, comments and all.
+    Settings given on the command line or in environment variables stay out of the file, since only the catalog is written.
+
+    The file is written under a temporary name and then moved into place, so a reader never sees it half-written.
+    The config watcher is told about the write, so it won't reload the file just because we saved it.
+
+    # Errors
+    Any filesystem error from reading, writing or renaming the file, or InvalidData if what's in it isn't TOML.
+    */
+    pub fn save(&self) -> io::Result<()>
+    {
+        let config = fs::read_to_string(PATH_CONFIG)?;
+        let updated = self.write_into(&config).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        let tmp = format!("{}.tmp", PATH_CONFIG);
+        fs::write(&tmp, updated)?;
+        fs::rename(&tmp, PATH_CONFIG)?;
+        if let Ok(mut saved) = LAST_SAVED.lock()
+        {
+            *saved = ConfigWatcher::modified();
+        }
+        Ok(())
+    }
+
+    /**
+    Put this catalog into the text of a config file, in place of the one there.
+
+    # Examples
+    ```
+    use metric_videos::settings::{Media, Video};
+    let config = "# where to listen\n[startup]\nlisten_addr = \"0.0.0.0:80\"\n\n[media]\nvideos = [\"http://example.com/old.mp4\"]\n";
+    let media = Media{videos: vec![Video::from_url("http://example.com/new.mp4")]};
+    let updated = media.write_into(config).unwrap();
+    assert!(updated.starts_with("# where to listen\n[startup]\nlisten_addr = \"0.0.0.0:80\"\n"));
+    assert!(updated.contains("[[media.videos]]\nid = \"new\""));
+    assert!(!updated.contains("old.mp4"));
+    ```
+    */
+    pub fn write_into(&self, config: &str) -> Result<String, String>
+    {
+        #[derive(Serialize)]
+        struct CatalogOnly<'a>
+        {
+            media: &'a Media
+        }
+
+        let mut doc: toml_edit::DocumentMut = config.parse().map_err(|e| format!("Couldn't read the config file: {}", e))?;
+        let catalog = toml::to_string(&CatalogOnly{media: self}).map_err(|e| format!("Couldn't write the catalog: {}", e))?;
+        let catalog: toml_edit::DocumentMut = catalog.parse().map_err(|e| format!("Couldn't read the catalog back: {}", e))?;
+        match doc.entry("media").or_insert_with(toml_edit::table).as_table_like_mut()
+        {
+            Some(media) => {media.insert("videos", catalog["media"]["videos"].clone());},
+            None => return Err(String::from("media in the config file isn't a table"))
+        }
+        Ok(doc.to_string())
+    }
 }
 
 /**
-The main type storing all the configuration data.
+One entry in the video catalog.
+*/
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+#[serde(from = "VideoEntry")]
+pub struct Video
+{
+    /// Stable identifier used to address the video in the API, so reordering the catalog doesn't mix up metrics
+    pub id: String,
+    pub title: String,
+    pub description: String,
+    /// URL of an image to show before the video starts playing
+    pub poster: Option<String>,
+    /// Length in seconds, if known ahead of time
+    pub duration: Option<u32>,
+    /// How long a watcher of this video may go without reporting before they're taken to have left, instead of the one under `[metrics]`
+    pub watcher_timeout_secs: Option<u32>,
+    pub tags: Vec<String>,
+    /// Alternative encodings of the video. The player uses the first one the browser can play.
+    pub sources: Vec<Source>
+}
+
+impl Video
+{
+    /**
+    Make a catalog entry out of nothing but the URL of a video file,
+    naming it after the file the way bare URLs were displayed before the catalog had titles.
+
+    # Examples
+    ```
+    use metric_videos::settings::Video;
+    let video = Video::from_url("http://example.com/vod/Big Buck Bunny.mp4");
+    assert_eq!(video.id, "big-buck-bunny");
+    assert_eq!(video.title, "Big Buck Bunny.mp4");
+    assert_eq!(video.sources[0].mime, "video/mp4");
+    ```
+    */
+    pub fn from_url(url: &str) -> Self
+    {
+        let path = Path::new(url);
+        let title = match path.file_name()
+        {
+            Some(s) => s.to_string_lossy().into_owned(),
+            None => url.to_owned()
+        };
+        let stem = path.file_stem().map(|s| s.to_string_lossy().into_owned()).unwrap_or_default();
+        let mut id = String::new();
+        for c in stem.chars()
+        {
+            if c.is_ascii_alphanumeric()
+            {
+                id.push(c.to_ascii_lowercase());
+            }else if !id.ends_with('-'){
+                id.push('-');
+            }
+        }
+        let id = id.trim_matches('-').to_owned();
+        Video{id, title, description: String::new(), poster: None, duration: None, watcher_timeout_secs: None, tags: Vec::new(), sources: vec![Source::from_url(url)]}
+    }
+}
+
+/**
+One encoding of a video, and where to get it.
+*/
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct Source
+{
+    pub url: String,
+    /// MIME type, like "video/mp4". Empty if unknown, in which case the browser has to figure it out.
+    #[serde(default)]
+    pub mime: String
+}
+
+impl Source
+{
+    /// Make a source for a URL, guessing its MIME type from the file extension.
+    pub fn from_url(url: &str) -> Self
+    {
+        let ext = Path::new(url).extension().map(|e| e.to_string_lossy().to_ascii_lowercase()).unwrap_or_default();
+        let mime = match ext.as_str()
+        {
+            "mp4" | "m4v" => "video/mp4",
+            "webm" => "video/webm",
+            "ogv" | "ogg" => "video/ogg",
+            "m3u8" => "application/vnd.apple.mpegurl",
+            _ => ""
+        };
+        Source{url: url.to_owned(), mime: mime.to_owned()}
+    }
+}
+
+/**
+How a video can be written in the config: either a full table, or just a URL as in the original format.
 */
 #[derive(Deserialize)]
+#[serde(untagged)]
+enum VideoEntry
+{
+    Url(String),
+    Full
+    {
+        id: String,
+        title: String,
+        #[serde(default)]
+        description: String,
+        #[serde(default)]
+        poster: Option<String>,
+        #[serde(default)]
+        duration: Option<u32>,
+        #[serde(default)]
+        watcher_timeout_secs: Option<u32>,
+        #[serde(default)]
+        tags: Vec<String>,
+        sources: Vec<Source>
+    }
+}
+
+impl From<VideoEntry> for Video
+{
+    fn from(entry: VideoEntry) -> Self
+    {
+        match entry
+        {
+            VideoEntry::Url(url) => Video::from_url(&url),
+            VideoEntry::Full{id, title, description, poster, duration, watcher_timeout_secs, tags, sources} => Video{id, title, description, poster, duration, watcher_timeout_secs, tags, sources}
+        }
+    }
+}
+
+/**
+The portion of the config describing the network we're deployed in.
+*/
+#[derive(Deserialize, Serialize, Clone, Default)]
+pub struct Network
+{
+    /// Reverse proxies allowed to tell us the real client address through `Forwarded`/`X-Forwarded-For`
+    pub trusted_proxies: Vec<Cidr>
+}
+
+/**
+The portion of the config deciding which watcher reports to believe.
+*/
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct Reports
+{
+    /// Whether reports must carry a playback token from the player page. Turn off only for players that predate tokens.
+    pub require_token: bool,
+    /// Fastest playback speed we believe, for telling real progress from made-up progress
+    pub max_speed: f32,
+    /// Reports from one watcher closer together than this many milliseconds are turned away
+    pub min_interval_ms: u32
+}
+
+impl Default for Reports
+{
+    fn default() -> Self
+    {
+        Reports{require_token: true, max_speed: 2.0, min_interval_ms: 500}
+    }
+}
+
+/**
+The portion of the config controlling how long watchers are kept in the live metrics.
+*/
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct Metrics
+{
+    /// How many seconds a watcher may go without reporting before they're taken to have left. Videos can set their own.
+    pub watcher_timeout_secs: u32,
+    /// How many seconds to wait between checks for watchers who have left
+    pub prune_interval_secs: u32
+}
+
+impl Default for Metrics
+{
+    fn default() -> Self
+    {
+        Metrics{watcher_timeout_secs: 5, prune_interval_secs: 2}
+    }
+}
+
+/**
+The portion of the config limiting how fast clients may make requests.
+*/
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct RateLimits
+{
+    /// Limit on watcher reports and WebSocket connections
+    pub watcher: Limit,
+    /// Limit on the dashboard's data, the Prometheus endpoint and the history
+    pub metrics: Limit,
+    /// Limit on tries at logging in through the login page, which applies to each address and to each user name tried
+    pub login: Limit
+}
+
+impl Default for RateLimits
+{
+    fn default() -> Self
+    {
+        RateLimits{
+            watcher: Limit{per_second: 2.0, burst: 10, by: LimitKey::Session},
+            metrics: Limit{per_second: 5.0, burst: 20, by: LimitKey::Ip},
+            login: Limit{per_second: 0.2, burst: 5, by: LimitKey::Ip}
+        }
+    }
+}
+
+/**
+A token bucket: each client may make `burst` requests at once, and gets back `per_second` of them every second.
+*/
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct Limit
+{
+    /// How many requests a second a client may keep up. Zero turns the limit off.
+    pub per_second: f64,
+    pub burst: u32,
+    pub by: LimitKey
+}
+
+/**
+What counts as one client for a rate limit.
+*/
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum LimitKey
+{
+    /// The client address, or for IPv6 its /64, since a client can pick any address in that
+    Ip,
+    /// The watcher session, from a valid playback token in the query string, or else the client address.
+    /// Lets many watchers share an address, as behind an office NAT, though one address still only gets 50 sessions' worth in all.
+    Session
+}
+
+/**
+The portion of the config controlling who may see the dashboard and metrics.
+Nobody may see them without logging in unless `allow_anonymous` is set; the admin API always needs the admin role.
+*/
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct Auth
+{
+    /// Whether anyone may see the dashboard, metrics and history without logging in, as before logins existed
+    pub allow_anonymous: bool,
+    /// Whether to accept HTTP basic credentials for the users below, in addition to logging in through the login page
+    pub basic: bool,
+    /// Key for signing login cookies. If empty, a random one is made at startup, which logs everyone out on every restart.
+    pub secret: String,
+    /// How long a login lasts before having to log in again
+    pub session_hours: u32,
+    pub users: Vec<User>,
+    pub tokens: Vec<ApiToken>
+}
+
+impl Default for Auth
+{
+    fn default() -> Self
+    {
+        Auth{allow_anonymous: false, basic: false, secret: String::new(), session_hours: 12, users: Vec::new(), tokens: Vec::new()}
+    }
+}
+
+/**
+Someone who can log in with a password.
+*/
+#[derive(Deserialize, Serialize, Clone)]
+pub struct User
+{
+    pub name: String,
+    /// Made by the `hash_password` program. The password itself is never stored.
+    pub password_hash: String,
+    pub role: Role
+}
+
+/**
+A secret that scripts and scrapers can send as `Authorization: Bearer <token>` instead of logging in.
+*/
+#[derive(Deserialize, Serialize, Clone)]
+pub struct ApiToken
+{
+    /// Who or what the token was issued to, for the logs
+    pub name: String,
+    pub token: String,
+    pub role: Role
+}
+
+/**
+The main type storing all the configuration data.
+*/
+#[derive(Deserialize, Serialize, Clone)]
 pub struct Settings
 {
     pub startup: Startup,
-    pub media: Media
+    pub media: Media,
+    #[serde(default)]
+    pub network: Network,
+    #[serde(default)]
+    pub metrics: Metrics,
+    #[serde(default)]
+    pub reports: Reports,
+    #[serde(default)]
+    pub rate_limits: RateLimits,
+    #[serde(default)]
+    pub auth: Auth
 }
 
 impl Settings
@@ -49,24 +450,41 @@ impl Settings
         },
         media: Media{
             videos: vec![
-                String::from("http://example.com/vid1.mp4"),
-                String::from("http://example.com/vid2.mp4")
+                Video{
+                    id: String::from("vid1"),
+                    title: String::from("First Video"),
+                    description: String::from("The one we made first"),
+                    poster: Some(String::from("http://example.com/vid1.jpg")),
+                    duration: Some(95),
+                    watcher_timeout_secs: None,
+                    tags: vec![String::from("example")],
+                    sources: vec![
+                        Source{url: String::from("http://example.com/vid1.webm"), mime: String::from("video/webm")},
+                        Source{url: String::from("http://example.com/vid1.mp4"), mime: String::from("video/mp4")}
+                    ]
+                },
+                Video::from_url("http://example.com/vid2.mp4")
             ]
-        }
+        },
+        network: Network{
+            trusted_proxies: vec!["127.0.0.1/32".parse().unwrap()]
+        },
+        metrics: Metrics::default(),
+        reports: Reports::default(),
+        rate_limits: RateLimits::default(),
+        auth: Auth::default()
     };
 
     let default_config_file_contents = def_settings.to_toml();
 
     assert_eq!(&default_config_file_contents[..30],"[startup]\nworking_dir = \"data\"");
+    assert!(default_config_file_contents.contains("[[media.videos]]\nid = \"vid1\"\ntitle = \"First Video\""));
+    assert!(default_config_file_contents.contains("[[media.videos.sources]]\nurl = \"http://example.com/vid1.webm\"\nmime = \"video/webm\""));
     ```
     */
     pub fn to_toml(&self) -> String
     {
-        format!("[startup]\nworking_dir = \"{}\"\nlisten_addr = \"{}\"\n[media]\nvideos = [\"{}\"]",
-            self.startup.working_dir,
-            self.startup.listen_addr,
-            self.media.videos.join("\",\"")
-        )
+        toml::to_string(self).expect("Settings should always be representable as TOML")
     }
 
     /**
@@ -85,42 +503,17 @@ impl Settings
 
     # Undefined behavior
     This should only be called once. Additional calls may result in issues with the underlying config and logger libraries.
+    To pick up changes to the config file later on, use `reload` instead.
 
     */
     fn new() -> Self
     {
-        let path_config = "config/config.toml";
         let path_log4rs_config = "config/log4rs.yml";
         //std::env::set_var("RUST_LOG", "my_errors=debug,actix_web=info");
         //std::env::set_var("RUST_BACKTRACE", "1");
         
         //Load command-line arguments. For those unspecified, load environment variables.
-        let cmd_matches = App::new("metric_videos")
-            .version("dev")
-            .about("actix-web app showing metrics of videos being watched through it")
-            .arg(Arg::with_name("working_dir")
-                .short("w")
-                .long("workingdir")
-                .env("metric_videos_WORKING_DIR")
-                .help("Working directory. Will look here for the folders config,history,logs,static -- particularly the config file in config/config.toml which will be created if it doesn't exist.")
-                .default_value(&DEFAULT_SETTINGS.startup.working_dir)
-                .takes_value(true))
-            .arg(Arg::with_name("listen_addr")
-                .short("l")
-                .long("listenaddr")
-                .env("metric_videos_LISTEN_ADDR")
-                .help("ip:port to listen on. Use 0.0.0.0 for the ip to listen on all interfaces.")
-                .default_value(&DEFAULT_SETTINGS.startup.listen_addr)
-                .takes_value(true))
-            .arg(Arg::with_name("videos")
-                .short("v")
-                .long("videos")
-                .env("metric_videos_video")
-                .help("URL to a video to be displayed")
-                .default_value("#default") //todo: CLAP 3.0 will allow specifying multivalued default instead of this placeholder
-                .takes_value(true)
-                .multiple(true))
-            .get_matches();
+        let cmd_matches = Settings::cmd_matches();
     
         //set cwd
         let working_dir = cmd_matches.value_of("working_dir").expect("Couldn't determine target working dir");
@@ -128,7 +521,7 @@ impl Settings
 
         //attempt to load config file
         let mut file_config = Config::new();
-        if let Err(ce) = file_config.merge(File::with_name(&path_config))
+        if let Err(ce) = file_config.merge(File::with_name(PATH_CONFIG))
         {
             match ce //determine reason for failure
             {
@@ -140,19 +533,16 @@ impl Settings
                 ConfigError::Message(e_str) => panic!("Couldn't load config because of the following: {}", e_str),
                 ConfigError::Foreign(_) =>{
                     //looks like the file is missing, attempt to write new file with defaults then load it. If this also fails then bail
-                    if let Err(e) = fs::write(String::from(path_config), DEFAULT_SETTINGS.to_toml()){
+                    if let Err(e) = fs::write(String::from(PATH_CONFIG), DEFAULT_SETTINGS.to_toml()){
                         panic!("Couldn't read main config file or write default main config file: {}", e);
                     }
-                    file_config.merge(File::with_name(&path_config)).expect("Couldn't load newly written default main config file.");
+                    file_config.merge(File::with_name(PATH_CONFIG)).expect("Couldn't load newly written default main config file.");
                 }
             }
         }
 
         //command line arguments, if given, override what is in the config file
-        let set_e = "Couldn't override config setting";
-        if cmd_matches.occurrences_of("working_dir"   ) > 0 {file_config.set("startup.working_dir", cmd_matches.value_of("working_dir"   )).expect(set_e);}
-        if cmd_matches.occurrences_of("listen_addr"   ) > 0 {file_config.set("startup.listen_addr", cmd_matches.value_of("listen_addr"   )).expect(set_e);}
-        if cmd_matches.occurrences_of("videos"        ) > 0 {file_config.set("media.videos",        cmd_matches.value_of("videos"        )).expect(set_e);}
+        Settings::apply_cmd_overrides(&mut file_config, &cmd_matches).expect("Couldn't override config setting");
 
         //attempt to load logging config
         if let Err(le) = log4rs::init_file(path_log4rs_config, Default::default())
@@ -172,24 +562,244 @@ impl Settings
         }
 
         //Export config to Settings struct
-        match file_config.try_into::<Settings>()
+        match Settings::export(file_config)
         {
-            Err(_) => {let e = "Couldn't export config."; error!("{}",e); panic!(e);},
-            Ok(mut s) => {
-                //todo: CLAP 3.0 will allow specifying multivalued default instead of this placeholder
-                if s.media.videos == vec![String::from("#settings")]
-                {
-                    s.media.videos = DEFAULT_SETTINGS.media.videos.to_owned();
-                }
-                s
-            }
+            Err(e) => {error!("{}",e); panic!("{}", e);},
+            Ok(s) => s
+        }
+    }
+
+    /**
+    Read the config file again, for picking up changes made while the app is running.
+
+    Command-line arguments and environment variables still override the file, just like at startup.
+    Unlike at startup, nothing is written if the file is missing, and no problem is fatal.
+
+    # Returns
+    The newly loaded settings. Nothing is swapped in: that's up to the caller, via `SETTINGS.replace`.
+
+    # Errors
+    A description of why the config couldn't be loaded: a missing or unparseable file, or an invalid video catalog.
+    */
+    pub fn reload() -> Result<Self, String>
+    {
+        let cmd_matches = Settings::cmd_matches();
+        let mut file_config = Config::new();
+        file_config.merge(File::with_name(PATH_CONFIG)).map_err(|e| format!("Couldn't load config: {}", e))?;
+        Settings::apply_cmd_overrides(&mut file_config, &cmd_matches).map_err(|e| format!("Couldn't override config setting: {}", e))?;
+        Settings::export(file_config)
+    }
+
+    /// Whether the video catalog in effect was given on the command line, in place of the one in the config file.
+    pub fn catalog_overridden() -> bool
+    {
+        Settings::cmd_matches().occurrences_of("videos") > 0
+    }
+
+    /// Parse the command line, falling back to environment variables for anything not given.
+    fn cmd_matches() -> ArgMatches<'static>
+    {
+        App::new("metric_videos")
+            .version("dev")
+            .about("actix-web app showing metrics of videos being watched through it")
+            .arg(Arg::with_name("working_dir")
+                .short("w")
+                .long("workingdir")
+                .env("metric_videos_WORKING_DIR")
+                .help("Working directory. Will look here for the folders config,history,logs,static -- particularly the config file in config/config.toml which will be created if it doesn't exist.")
+                .default_value(&DEFAULT_SETTINGS.startup.working_dir)
+                .takes_value(true))
+            .arg(Arg::with_name("listen_addr")
+                .short("l")
+                .long("listenaddr")
+                .env("metric_videos_LISTEN_ADDR")
+                .help("ip:port to listen on. Use 0.0.0.0 for the ip to listen on all interfaces.")
+                .default_value(&DEFAULT_SETTINGS.startup.listen_addr)
+                .takes_value(true))
+            .arg(Arg::with_name("videos")
+                .short("v")
+                .long("videos")
+                .env("metric_videos_video")
+                .help("URL to a video to be displayed")
+                .default_value("#default") //todo: CLAP 3.0 will allow specifying multivalued default instead of this placeholder
+                .takes_value(true)
+                .multiple(true))
+            .arg(Arg::with_name("watcher_timeout")
+                .long("watchertimeout")
+                .env("metric_videos_WATCHER_TIMEOUT")
+                .help("Seconds a watcher may go without reporting before they're taken to have left, for videos that don't set their own.")
+                .validator(is_seconds)
+                .takes_value(true))
+            .arg(Arg::with_name("prune_interval")
+                .long("pruneinterval")
+                .env("metric_videos_PRUNE_INTERVAL")
+                .help("Seconds between checks for watchers who have left.")
+                .validator(is_seconds)
+                .takes_value(true))
+            .subcommand(SubCommand::with_name("report")
+                .about("Print the daily summary for a day, instead of starting the server")
+                .arg(Arg::with_name("date")
+                    .help("UTC day to report on, like 2020-09-13. Today if left out.")
+                    .validator(is_date)))
+            .get_matches()
+    }
+
+    /// Command line arguments, if given, override what is in the config file.
+    fn apply_cmd_overrides(file_config: &mut Config, cmd_matches: &ArgMatches) -> Result<(), ConfigError>
+    {
+        if cmd_matches.occurrences_of("working_dir"   ) > 0 {file_config.set("startup.working_dir", cmd_matches.value_of("working_dir"   ))?;}
+        if cmd_matches.occurrences_of("listen_addr"   ) > 0 {file_config.set("startup.listen_addr", cmd_matches.value_of("listen_addr"   ))?;}
+        if cmd_matches.occurrences_of("videos"        ) > 0 {file_config.set("media.videos",        cmd_matches.values_of("videos").map(|v| v.map(String::from).collect::<Vec<String>>()))?;}
+        //these have no default on the command line, so a value from either place overrides the file
+        if let Some(secs) = cmd_matches.value_of("watcher_timeout") {file_config.set("metrics.watcher_timeout_secs", secs.parse::<i64>().unwrap_or_default())?;}
+        if let Some(secs) = cmd_matches.value_of("prune_interval" ) {file_config.set("metrics.prune_interval_secs",  secs.parse::<i64>().unwrap_or_default())?;}
+        Ok(())
+    }
+
+    /**
+    Export merged config to Settings struct, checking it over before handing it out.
+    A `token` under `[admin]`, from before there were roles, is taken as one of the tokens under `[auth]` with the admin role.
+    */
+    fn export(file_config: Config) -> Result<Self, String>
+    {
+        let old_admin_token = file_config.get_str("admin.token").unwrap_or_default();
+        let mut s = file_config.try_into::<Settings>().map_err(|e| format!("Couldn't export config: {}", e))?;
+        s.media.validate().map_err(|e| format!("Invalid video catalog: {}", e))?;
+        if !old_admin_token.is_empty()
+        {
+            warn!("The token under [admin] is deprecated; move it to [[auth.tokens]] with role = \"admin\"");
+            s.auth.tokens.push(ApiToken{name: String::from("admin token"), token: old_admin_token, role: Role::Admin});
+        }
+        Ok(s)
+    }
+}
+
+/// Check that a command line argument is a day, like 2020-09-13.
+fn is_date(value: String) -> Result<(), String>
+{
+    NaiveDate::parse_from_str(&value, "%Y-%m-%d").map(|_| ()).map_err(|_| format!("\"{}\" isn't a day like 2020-09-13", value))
+}
+
+/// Check that a command line argument is a whole number of seconds.
+fn is_seconds(value: String) -> Result<(), String>
+{
+    value.parse::<u32>().map(|_| ()).map_err(|_| format!("\"{}\" isn't a whole number of seconds", value))
+}
+
+/**
+What the command line asks the app to do.
+*/
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Command
+{
+    /// Run the server, which is what happens unless something else is asked for
+    Serve,
+    /// Print the daily summary for a day, or for today if none is given
+    Report(Option<NaiveDate>)
+}
+
+impl Command
+{
+    /// Work out what the command line asks for. Anything wrong with it has already stopped the app while loading the settings.
+    pub fn from_args() -> Self
+    {
+        match Settings::cmd_matches().subcommand_matches("report")
+        {
+            Some(report) => Command::Report(report.value_of("date").and_then(|d| NaiveDate::parse_from_str(d, "%Y-%m-%d").ok())),
+            None => Command::Serve
+        }
+    }
+}
+
+/**
+Holds the settings currently in effect, which can be swapped for new ones while the app runs.
+
+Readers get an `Arc` to a complete, consistent set of settings, which stays valid even if it's replaced in the meantime.
+*/
+pub struct LiveSettings
+{
+    current: RwLock<Arc<Settings>>
+}
+
+impl LiveSettings
+{
+    /// The settings currently in effect.
+    pub fn get(&self) -> Arc<Settings>
+    {
+        match self.current.read()
+        {
+            Ok(g) => Arc::clone(&g),
+            Err(poisoned) => Arc::clone(&poisoned.into_inner())
+        }
+    }
+
+    /**
+    Put new settings into effect for everything that reads them from now on.
+
+    # Returns
+    The settings that were in effect before.
+    */
+    pub fn replace(&self, settings: Settings) -> Arc<Settings>
+    {
+        let mut g = match self.current.write()
+        {
+            Ok(g) => g,
+            Err(poisoned) => poisoned.into_inner()
+        };
+        std::mem::replace(&mut *g, Arc::new(settings))
+    }
+}
+
+/**
+Notices when the config file changes, by checking its modification time.
+*/
+pub struct ConfigWatcher
+{
+    modified: Option<SystemTime>
+}
+
+impl ConfigWatcher
+{
+    /// Start watching, treating the file as it is now as already loaded.
+    pub fn new() -> Self
+    {
+        ConfigWatcher{modified: ConfigWatcher::modified()}
+    }
+
+    /**
+    Check whether the file has changed since the last time this returned true (or since this watcher was made).
+    Changes made by `Settings::save` don't count, since those settings are already in effect.
+    */
+    pub fn changed(&mut self) -> bool
+    {
+        let now = ConfigWatcher::modified();
+        if now.is_some() && now != self.modified
+        {
+            self.modified = now;
+            let saved = LAST_SAVED.lock().map(|g| *g).unwrap_or(None);
+            return now != saved;
         }
+        false
+    }
+
+    fn modified() -> Option<SystemTime>
+    {
+        fs::metadata(PATH_CONFIG).and_then(|m| m.modified()).ok()
+    }
+}
+
+impl Default for ConfigWatcher {
+    fn default() -> Self {
+        ConfigWatcher::new()
     }
 }
 
 lazy_static!
 {
-    pub static ref SETTINGS: Settings = Settings::new();
+    /// Modification time of the config file as of our last write to it
+    static ref LAST_SAVED: Mutex<Option<SystemTime>> = Mutex::new(None);
+
+    pub static ref SETTINGS: LiveSettings = LiveSettings{current: RwLock::new(Arc::new(Settings::new()))};
 
     static ref DEFAULT_SETTINGS: Settings = Settings{
         startup: Startup{
@@ -197,11 +807,46 @@ lazy_static!
             listen_addr: String::from("0.0.0.0:80")
         },
         media: Media{
-            videos: vec![String::from("http://reflect-tightytv-vod.cablecast.tv/vod/2-TRMS-Medium-v1.mp4"),
-                String::from("http://reflect-tightytv-vod.cablecast.tv/vod/52-CTV-Needs-Interns-Promo-High-v1.mp4"),
-                String::from("http://reflect-tightytv-vod.cablecast.tv/vod/3-NAB-2014-Artbeats-30min-High-v4.mp4")
+            videos: vec![
+                Video{
+                    id: String::from("trms-medium"),
+                    title: String::from("Tightrope Media Systems"),
+                    description: String::from("A short introduction to Tightrope Media Systems."),
+                    poster: None,
+                    duration: None,
+                    watcher_timeout_secs: None,
+                    tags: vec![String::from("promo")],
+                    sources: vec![Source::from_url("http://reflect-tightytv-vod.cablecast.tv/vod/2-TRMS-Medium-v1.mp4")]
+                },
+                Video{
+                    id: String::from("ctv-needs-interns"),
+                    title: String::from("CTV Needs Interns"),
+                    description: String::from("Promo calling for interns at CTV."),
+                    poster: None,
+                    duration: None,
+                    watcher_timeout_secs: None,
+                    tags: vec![String::from("promo")],
+                    sources: vec![Source::from_url("http://reflect-tightytv-vod.cablecast.tv/vod/52-CTV-Needs-Interns-Promo-High-v1.mp4")]
+                },
+                Video{
+                    id: String::from("nab-2014-artbeats"),
+                    title: String::from("NAB 2014 Artbeats"),
+                    description: String::from("Thirty minutes of Artbeats stock footage from NAB 2014."),
+                    poster: None,
+                    duration: Some(1800),
+                    watcher_timeout_secs: None,
+                    tags: vec![String::from("stock footage"), String::from("long form")],
+                    sources: vec![Source::from_url("http://reflect-tightytv-vod.cablecast.tv/vod/3-NAB-2014-Artbeats-30min-High-v4.mp4")]
+                }
             ]
-        }
+        },
+        network: Network{
+            trusted_proxies: Vec::new()
+        },
+        metrics: Metrics::default(),
+        reports: Reports::default(),
+        rate_limits: RateLimits::default(),
+        auth: Auth::default()
     };
 
     static ref DEFAULT_LOG4RS: String = String::from("refresh_rate: 60 seconds
@@ -251,4 +896,17 @@ mod tests
         //if this function panics, that is what will make the test fail, so no assert is needed.
         let _config = Settings::new();
     }
+
+    // settings::Video deserialization of the original bare-URL catalog format
+    #[test]
+    fn config_old_video_format()
+    {
+        let mut c = Config::new();
+        c.merge(File::from_str("[startup]\nworking_dir = \"data\"\nlisten_addr = \"0.0.0.0:80\"\n[media]\nvideos = [\"http://example.com/a.webm\",\"http://example.com/b.mp4\"]", config::FileFormat::Toml)).unwrap();
+        let s: Settings = c.try_into().expect("Couldn't read old format");
+        assert_eq!(s.media.videos, vec![Video::from_url("http://example.com/a.webm"), Video::from_url("http://example.com/b.mp4")]);
+        assert_eq!(s.media.videos[0].sources[0].mime, "video/webm");
+        assert!(s.media.validate().is_ok());
+        assert!(s.network.trusted_proxies.is_empty());
+    }
 }
\ No newline at end of file
@@ -0,0 +1,359 @@
+use chrono::{NaiveDate, NaiveDateTime, Utc};
+use log::{error, /*warn, info, debug, trace, log, Level*/};
+
+use std::collections::{BTreeMap, HashSet};
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard, PoisonError};
+
+use crate::history::{self, day_of, Session};
+
+/// Folder, relative to the working dir, where each day's peaks are kept.
+const SUMMARY_DIR: &str = "summaries";
+
+/**
+The most watchers there were at once, and when.
+*/
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, Default, PartialEq)]
+pub struct Peak
+{
+    pub viewers: u32,
+    /// Unix time of the first sample with that many
+    pub at: i64
+}
+
+impl Peak
+{
+    fn sample(&mut self, now: i64, viewers: u32)
+    {
+        if viewers > self.viewers
+        {
+            *self = Peak{viewers, at: now};
+        }
+    }
+}
+
+/**
+The peaks of one UTC day, for all the videos together and for each one that had any watchers.
+*/
+#[derive(Deserialize, Serialize, Clone, Debug, Default, PartialEq)]
+pub struct DayPeaks
+{
+    pub total: Peak,
+    pub videos: BTreeMap<String, Peak>
+}
+
+/**
+Keeps track of the peaks of the current day, as the updater samples how many watchers each video has.
+*/
+pub struct PeakTracker
+{
+    day: NaiveDate,
+    peaks: DayPeaks
+}
+
+impl PeakTracker
+{
+    /// Start tracking a day, carrying on from the peaks it already had.
+    pub fn new(day: NaiveDate, peaks: DayPeaks) -> Self
+    {
+        PeakTracker{day, peaks}
+    }
+
+    /**
+    Take in how many watchers each video has at one moment.
+
+    # Parameters
+    - `now`: current unix timestamp
+    - `counts`: catalog id and number of watchers of every video in the catalog
+
+    # Returns
+    The finished day and its peaks, if this sample is from a new day.
+    */
+    pub fn sample(&mut self, now: i64, counts: &[(String, u32)]) -> Option<(NaiveDate, DayPeaks)>
+    {
+        let day = day_of(now);
+        let finished = if day != self.day
+        {
+            let old = std::mem::replace(self, PeakTracker::new(day, DayPeaks::default()));
+            Some((old.day, old.peaks))
+        }else{
+            None
+        };
+
+        self.peaks.total.sample(now, counts.iter().map(|(_, n)| n).sum());
+        for (video, n) in counts.iter().filter(|(_, n)| *n > 0)
+        {
+            self.peaks.videos.entry(video.clone()).or_default().sample(now, *n);
+        }
+        finished
+    }
+}
+
+/**
+The numbers for one video, or for all of them together, over one day.
+*/
+#[derive(Serialize, Clone, Debug, Default, PartialEq)]
+pub struct Summary
+{
+    /// Catalog id of the video, or none for all the videos together
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub video: Option<String>,
+    /// Viewing sessions that ended that day
+    pub sessions: u64,
+    /// Different addresses those sessions came from
+    pub unique_viewers: u64,
+    /// Seconds from the first report to the last of each of those sessions, added up
+    pub watch_time_secs: i64,
+    /// How many of those sessions played through to the end, and what share of them that is
+    pub completed: u64,
+    pub completion_rate: f64,
+    /// The most watchers there were at once, and when
+    pub peak: Peak
+}
+
+/**
+The numbers for one day, as served by the summary endpoint and printed by the `report` command.
+*/
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct DailySummary
+{
+    /// The UTC day, like "2020-09-13"
+    pub date: String,
+    pub total: Summary,
+    /// One for each video that had any watchers, ordered by id
+    pub videos: Vec<Summary>
+}
+
+impl DailySummary
+{
+    /**
+    Work out the numbers for one day.
+
+    # Parameters
+    - `date`: the UTC day
+    - `sessions`: the viewing sessions that ended that day
+    - `peaks`: the peaks of that day
+
+    # Examples
+    ```
+    use metric_videos::history::Session;
+    use metric_videos::summary::{DailySummary, DayPeaks, Peak};
+    let session = |ip: [u8; 4], start: i64, end: i64, completed: bool| Session{
+        video: String::from("intro"), video_index: None, session: String::new(), ip: Some(ip.into()), start, end, max_percent: 100, completed
+    };
+    let mut peaks = DayPeaks{total: Peak{viewers: 2, at: 1_600_000_050}, ..DayPeaks::default()};
+    peaks.videos.insert(String::from("intro"), peaks.total);
+
+    let date = chrono::NaiveDate::from_ymd(2020, 9, 13);
+    let sessions = vec![session([192, 0, 2, 1], 1_600_000_000, 1_600_000_100, true), session([192, 0, 2, 1], 1_600_000_020, 1_600_000_060, false)];
+    let summary = DailySummary::of(date, &sessions, &peaks);
+    assert_eq!(summary.date, "2020-09-13");
+    assert_eq!((summary.total.sessions, summary.total.unique_viewers, summary.total.watch_time_secs), (2, 1, 140));
+    assert_eq!((summary.total.completed, summary.total.completion_rate), (1, 0.5));
+    assert_eq!(summary.videos[0].peak, Peak{viewers: 2, at: 1_600_000_050});
+    ```
+    */
+    pub fn of(date: NaiveDate, sessions: &[Session], peaks: &DayPeaks) -> Self
+    {
+        let mut videos: BTreeMap<&str, (Summary, HashSet<String>)> = BTreeMap::new();
+        let mut total = (Summary{peak: peaks.total, ..Summary::default()}, HashSet::new());
+        for (video, peak) in &peaks.videos
+        {
+            videos.entry(video.as_str()).or_default().0.peak = *peak;
+        }
+        for session in sessions
+        {
+            //players without an address to go by count as viewers of their own
+            let viewer = session.ip.map_or_else(|| session.session.clone(), |ip| ip.to_string());
+            for (summary, viewers) in [videos.entry(&session.video).or_default(), &mut total]
+            {
+                summary.sessions += 1;
+                summary.watch_time_secs += (session.end - session.start).max(0);
+                summary.completed += u64::from(session.completed);
+                viewers.insert(viewer.clone());
+            }
+        }
+
+        let finish = |(mut summary, viewers): (Summary, HashSet<String>), video: Option<&str>| {
+            summary.video = video.map(String::from);
+            summary.unique_viewers = viewers.len() as u64;
+            summary.completion_rate = if summary.sessions == 0 {0.0} else {summary.completed as f64 / summary.sessions as f64};
+            summary
+        };
+        DailySummary{
+            date: date.format("%Y-%m-%d").to_string(),
+            total: finish(total, None),
+            videos: videos.into_iter().map(|(video, s)| finish(s, Some(video))).collect()
+        }
+    }
+}
+
+/// Lays the numbers out as a table, for the `report` command.
+impl fmt::Display for DailySummary
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        writeln!(f, "Viewing on {} (UTC)", self.date)?;
+        writeln!(f, "{:<24} {:>9} {:>8} {:>11} {:>10} {:>5}  peak at", "video", "sessions", "viewers", "watch time", "completed", "peak")?;
+        for s in std::iter::once(&self.total).chain(&self.videos)
+        {
+            let peak_at = match NaiveDateTime::from_timestamp_opt(s.peak.at, 0)
+            {
+                Some(t) if s.peak.viewers > 0 => t.format("%H:%M:%S").to_string(),
+                _ => String::from("-")
+            };
+            writeln!(f, "{:<24} {:>9} {:>8} {:>11} {:>9.0}% {:>5}  {}",
+                s.video.as_deref().unwrap_or("(all videos)"), s.sessions, s.unique_viewers, hours(s.watch_time_secs), s.completion_rate * 100.0, s.peak.viewers, peak_at)?;
+        }
+        Ok(())
+    }
+}
+
+/// Write a number of seconds as hours, minutes and seconds, like "12:05:09".
+fn hours(secs: i64) -> String
+{
+    format!("{}:{:02}:{:02}", secs / 3600, secs % 3600 / 60, secs % 60)
+}
+
+/**
+Work out the numbers for one day, from the sessions in the history and the peaks the updater kept.
+
+# Parameters
+- `date`: the UTC day
+
+# Errors
+Any filesystem error from reading the history or the day's peaks.
+*/
+pub fn daily(date: NaiveDate) -> io::Result<DailySummary>
+{
+    let start = date.and_hms(0, 0, 0).timestamp();
+    let sessions = history::query(start, start + 86399)?;
+    let peaks = {
+        let tracker = lock(&PEAKS);
+        if tracker.day == date {Some(tracker.peaks.clone())} else {None}
+    };
+    let peaks = match peaks
+    {
+        Some(p) => p,
+        None => load_in(Path::new(SUMMARY_DIR), date)?
+    };
+    Ok(DailySummary::of(date, &sessions, &peaks))
+}
+
+/**
+Write the current day's peaks to the working dir, so a restart carries on from them.
+
+# Errors
+Any filesystem error from creating the folder or writing the file.
+*/
+pub fn save() -> io::Result<()>
+{
+    let (day, peaks) = {
+        let tracker = lock(&PEAKS);
+        (tracker.day, tracker.peaks.clone())
+    };
+    save_peaks(day, &peaks)
+}
+
+/**
+Write the peaks of one day to the working dir, replacing any written before.
+
+# Errors
+Any filesystem error from creating the folder or writing the file.
+*/
+pub fn save_peaks(day: NaiveDate, peaks: &DayPeaks) -> io::Result<()>
+{
+    save_in(Path::new(SUMMARY_DIR), day, peaks)
+}
+
+fn day_file(dir: &Path, day: NaiveDate) -> PathBuf
+{
+    dir.join(format!("{}.json", day.format("%Y-%m-%d")))
+}
+
+fn save_in(dir: &Path, day: NaiveDate, peaks: &DayPeaks) -> io::Result<()>
+{
+    fs::create_dir_all(dir)?;
+    let path = day_file(dir, day);
+    let tmp = path.with_extension("json.tmp");
+    fs::write(&tmp, serde_json::to_string(peaks)?)?;
+    fs::rename(&tmp, path)
+}
+
+/// Read the peaks saved for one day. A day without a file had no watchers, rather than being an error.
+fn load_in(dir: &Path, day: NaiveDate) -> io::Result<DayPeaks>
+{
+    let path = day_file(dir, day);
+    if !path.exists()
+    {
+        return Ok(DayPeaks::default());
+    }
+    Ok(serde_json::from_slice(&fs::read(path)?)?)
+}
+
+/// Lock the peak tracker, carrying on with it even if a panic left the lock poisoned, since every change to it is a single step.
+pub fn lock(m: &Mutex<PeakTracker>) -> MutexGuard<'_, PeakTracker>
+{
+    m.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+lazy_static!
+{
+    pub static ref PEAKS: Mutex<PeakTracker> = {
+        let today = Utc::now().naive_utc().date();
+        Mutex::new(PeakTracker::new(today, load_in(Path::new(SUMMARY_DIR), today).unwrap_or_else(|e| {
+            error!("Couldn't read today's peaks, starting them over: {}", e);
+            DayPeaks::default()
+        })))
+    };
+}
+
+/*
+Test the peak tracking and the file format against a scratch folder, since the public functions
+always work relative to the working dir
+*/
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    // summary::PeakTracker::sample()
+    #[test]
+    fn peaks_by_day()
+    {
+        let start = 1_600_000_000;
+        let counts = |a: u32, b: u32| vec![(String::from("a"), a), (String::from("b"), b)];
+        let mut tracker = PeakTracker::new(day_of(start), DayPeaks::default());
+        assert!(tracker.sample(start, &counts(1, 0)).is_none());
+        assert!(tracker.sample(start + 10, &counts(3, 1)).is_none());
+        assert!(tracker.sample(start + 20, &counts(2, 2)).is_none());
+        assert_eq!(tracker.peaks.total, Peak{viewers: 4, at: start + 10});
+        assert_eq!(tracker.peaks.videos["b"], Peak{viewers: 2, at: start + 20});
+
+        //the next day starts over, handing back the one before
+        let (day, peaks) = tracker.sample(start + 86400, &counts(1, 0)).unwrap();
+        assert_eq!((day, peaks.videos["a"].viewers), (day_of(start), 3));
+        assert_eq!(tracker.peaks.total, Peak{viewers: 1, at: start + 86400});
+        assert!(!tracker.peaks.videos.contains_key("b"));
+    }
+
+    // summary::save_peaks() and the loading of PEAKS
+    #[test]
+    fn save_and_load()
+    {
+        let dir = std::env::temp_dir().join(format!("metric_videos_summaries_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let day = NaiveDate::from_ymd(2020, 9, 13);
+        assert_eq!(load_in(&dir, day).unwrap(), DayPeaks::default());
+
+        let mut peaks = DayPeaks{total: Peak{viewers: 5, at: 1_600_000_000}, ..DayPeaks::default()};
+        peaks.videos.insert(String::from("a"), peaks.total);
+        save_in(&dir, day, &peaks).unwrap();
+        assert_eq!(load_in(&dir, day).unwrap(), peaks);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,417 @@
+use log::{error, /*warn, info, debug, trace, log, Level*/};
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::sync::{Mutex, MutexGuard, PoisonError};
+
+/// File, relative to the working dir, where the time series are kept between runs.
+const TIMESERIES_FILE: &str = "timeseries.json";
+
+/// Most points one query may ask for, so a tiny step over a long range can't make a huge response.
+pub const MAX_POINTS: i64 = 10_000;
+
+/// The resolutions each series is kept at, as seconds per point and how many points: a second for an hour, a minute for a day, and an hour for 30 days.
+const RESOLUTIONS: [(i64, usize); 3] = [(1, 3600), (60, 1440), (3600, 720)];
+
+/**
+How many watchers there were over one stretch of time, from however many samples fell in it.
+*/
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq)]
+struct Bucket
+{
+    /// Unix time the stretch starts at
+    t: i64,
+    max: u32,
+    sum: u64,
+    samples: u32
+}
+
+impl Bucket
+{
+    fn merge(&mut self, other: &Bucket)
+    {
+        self.max = self.max.max(other.max);
+        self.sum += other.sum;
+        self.samples += other.samples;
+    }
+}
+
+/**
+The buckets of one resolution, going round in a fixed amount of room, so the oldest are overwritten by new ones.
+*/
+struct Ring
+{
+    step: i64,
+    slots: Vec<Option<Bucket>>
+}
+
+impl Ring
+{
+    fn new(step: i64, len: usize) -> Self
+    {
+        Ring{step, slots: vec![None; len]}
+    }
+
+    /// How far back this goes from a given time, in seconds.
+    fn span(&self) -> i64
+    {
+        self.step * self.slots.len() as i64
+    }
+
+    /// Add to the bucket a sample or bucket belongs in, replacing whatever was in its slot from a previous time round.
+    fn put(&mut self, bucket: Bucket)
+    {
+        let t = bucket.t - bucket.t.rem_euclid(self.step);
+        let index = (t / self.step).rem_euclid(self.slots.len() as i64) as usize;
+        match &mut self.slots[index]
+        {
+            Some(b) if b.t == t => b.merge(&bucket),
+            slot => *slot = Some(Bucket{t, ..bucket})
+        }
+    }
+
+    /// The buckets that start within a range, in order.
+    fn range(&self, from: i64, to: i64) -> Vec<Bucket>
+    {
+        let mut buckets: Vec<Bucket> = self.slots.iter().flatten().filter(|b| b.t >= from && b.t <= to).copied().collect();
+        buckets.sort_by_key(|b| b.t);
+        buckets
+    }
+}
+
+/// The rings of one series, finest first.
+struct Series
+{
+    rings: Vec<Ring>
+}
+
+impl Series
+{
+    fn new() -> Self
+    {
+        Series{rings: RESOLUTIONS.iter().map(|(step, len)| Ring::new(*step, *len)).collect()}
+    }
+
+    fn put(&mut self, bucket: Bucket)
+    {
+        for ring in &mut self.rings
+        {
+            ring.put(bucket);
+        }
+    }
+}
+
+/**
+How many watchers each video has had over time, sampled by the updater, and kept at coarser resolutions the further back it goes.
+*/
+pub struct Timeseries
+{
+    /// Keyed by catalog id, with the empty string for all the videos together
+    series: BTreeMap<String, Series>
+}
+
+/// The time series as they're saved to disk: every bucket of every ring, finest first.
+#[derive(Deserialize, Serialize)]
+struct Saved
+{
+    videos: BTreeMap<String, Vec<Vec<Bucket>>>
+}
+
+/**
+Query string accepted by the timeseries endpoint. The range is in unix timestamps, and the step in seconds.
+*/
+#[derive(Deserialize, Clone, Debug, Default)]
+pub struct TimeseriesQuery
+{
+    /// Catalog id of the video, or none for all the videos together
+    pub video: Option<String>,
+    pub from: Option<i64>,
+    pub to: Option<i64>,
+    pub step: Option<i64>
+}
+
+/**
+How many watchers there were over a range of time, as served by the timeseries endpoint.
+*/
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct TimeseriesReport
+{
+    /// Catalog id of the video, or none for all the videos together
+    pub video: Option<String>,
+    pub from: i64,
+    pub to: i64,
+    /// Seconds per point, which is never finer than what's kept for that far back
+    pub step: i64,
+    /// One for each step of the range that has samples in it. Steps without any, like while the app wasn't running, are left out.
+    pub points: Vec<TimeseriesPoint>
+}
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq)]
+pub struct TimeseriesPoint
+{
+    /// Unix time the step starts at
+    pub t: i64,
+    /// Most watchers at any one sample
+    pub max: u32,
+    /// Mean number of watchers over the samples
+    pub mean: f64
+}
+
+impl Timeseries
+{
+    pub fn new() -> Self
+    {
+        Timeseries{series: BTreeMap::new()}
+    }
+
+    /**
+    Record how many watchers each video has at one moment, along with the total.
+
+    # Parameters
+    - `now`: current unix timestamp
+    - `counts`: catalog id and number of watchers of every video in the catalog
+    */
+    pub fn sample(&mut self, now: i64, counts: &[(String, u32)])
+    {
+        let total: u32 = counts.iter().map(|(_, n)| n).sum();
+        for (video, n) in counts.iter().map(|(v, n)| (v.as_str(), *n)).chain(std::iter::once(("", total)))
+        {
+            let series = self.series.entry(video.to_owned()).or_insert_with(Series::new);
+            series.put(Bucket{t: now, max: n, sum: u64::from(n), samples: 1});
+        }
+    }
+
+    /**
+    Look up how many watchers there were over a range of time.
+
+    # Parameters
+    - `query`: which video, and the range and step wanted. `to` defaults to now, `from` to an hour before `to`,
+      and `step` to the finest resolution that's kept that far back.
+    - `now`: current unix timestamp, for working out which resolutions reach back far enough
+
+    # Returns
+    The points, or None if nothing has ever been sampled for the video.
+
+    # Errors
+    A description of what's wrong with the query: a range that ends before it starts, a step that isn't positive, or too many points.
+
+    # Examples
+    ```
+    use metric_videos::timeseries::{Timeseries, TimeseriesQuery};
+    let mut timeseries = Timeseries::new();
+    timeseries.sample(1_000_020, &[(String::from("intro"), 2)]);
+    timeseries.sample(1_000_050, &[(String::from("intro"), 4)]);
+
+    let query = TimeseriesQuery{video: Some(String::from("intro")), from: Some(1_000_000), step: Some(60), ..TimeseriesQuery::default()};
+    let report = timeseries.query(&query, 1_000_060).unwrap().unwrap();
+    assert_eq!(report.points.len(), 1);
+    assert_eq!((report.points[0].t, report.points[0].max, report.points[0].mean), (1_000_020, 4, 3.0));
+    ```
+    */
+    pub fn query(&self, query: &TimeseriesQuery, now: i64) -> Result<Option<TimeseriesReport>, String>
+    {
+        let to = query.to.unwrap_or(now);
+        let from = query.from.unwrap_or_else(|| to.saturating_sub(3600));
+        if from > to
+        {
+            return Err(String::from("from must not be after to"));
+        }
+        if query.step.is_some_and(|s| s <= 0)
+        {
+            return Err(String::from("step must be more than zero"));
+        }
+        let series = match self.series.get(query.video.as_deref().unwrap_or(""))
+        {
+            Some(s) => s,
+            None => return Ok(None)
+        };
+
+        //the finest ring that reaches back far enough, or else the coarsest there is
+        let ring = series.rings.iter().find(|r| from >= now - r.span()).unwrap_or_else(|| &series.rings[series.rings.len() - 1]);
+        let step = query.step.unwrap_or(ring.step).max(ring.step);
+        //the range comes straight from the query string, so it may be too wide to even work out
+        let too_many = || format!("that would be more than {} points, so use a longer step", MAX_POINTS);
+        if to.checked_sub(from).ok_or_else(too_many)? / step >= MAX_POINTS
+        {
+            return Err(too_many());
+        }
+        let start = from.checked_sub(from.rem_euclid(step)).ok_or_else(|| String::from("from is too far in the past"))?;
+
+        let mut steps: Vec<Bucket> = Vec::new();
+        for bucket in ring.range(start, to)
+        {
+            let t = bucket.t - bucket.t.rem_euclid(step);
+            match steps.last_mut()
+            {
+                Some(last) if last.t == t => last.merge(&bucket),
+                _ => steps.push(Bucket{t, ..bucket})
+            }
+        }
+        Ok(Some(TimeseriesReport{
+            video: query.video.clone(),
+            from,
+            to,
+            step,
+            points: steps.iter().map(|b| TimeseriesPoint{t: b.t, max: b.max, mean: b.sum as f64 / f64::from(b.samples.max(1))}).collect()
+        }))
+    }
+
+    fn saved(&self) -> Saved
+    {
+        Saved{videos: self.series.iter().map(|(video, series)| {
+            (video.clone(), series.rings.iter().map(|r| r.slots.iter().flatten().copied().collect()).collect())
+        }).collect()}
+    }
+
+    fn from_saved(saved: Saved) -> Self
+    {
+        let mut timeseries = Timeseries::new();
+        for (video, rings) in saved.videos
+        {
+            let mut series = Series::new();
+            for (ring, buckets) in series.rings.iter_mut().zip(rings)
+            {
+                for bucket in buckets
+                {
+                    ring.put(bucket);
+                }
+            }
+            timeseries.series.insert(video, series);
+        }
+        timeseries
+    }
+}
+
+impl Default for Timeseries
+{
+    fn default() -> Self
+    {
+        Timeseries::new()
+    }
+}
+
+/**
+Write the time series to the working dir, so they carry on from where they were after a restart.
+The file is replaced whole, by writing a new one and moving it into place, so a crash partway through leaves the old one.
+
+# Errors
+Any filesystem error from writing the file.
+*/
+pub fn save() -> io::Result<()>
+{
+    let json = serde_json::to_string(&lock(&TIMESERIES).saved())?;
+    save_in(Path::new(TIMESERIES_FILE), &json)
+}
+
+fn save_in(path: &Path, json: &str) -> io::Result<()>
+{
+    let tmp = path.with_extension("json.tmp");
+    fs::write(&tmp, json)?;
+    fs::rename(&tmp, path)
+}
+
+/// Read time series saved by an earlier run. A missing file is no time series yet, rather than an error.
+fn load_in(path: &Path) -> io::Result<Timeseries>
+{
+    if !path.exists()
+    {
+        return Ok(Timeseries::new());
+    }
+    let saved: Saved = serde_json::from_slice(&fs::read(path)?)?;
+    Ok(Timeseries::from_saved(saved))
+}
+
+/// Lock the time series, carrying on with them even if a panic left the lock poisoned, since every change to them is a single step.
+pub fn lock(m: &Mutex<Timeseries>) -> MutexGuard<'_, Timeseries>
+{
+    m.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+lazy_static!
+{
+    pub static ref TIMESERIES: Mutex<Timeseries> = Mutex::new(match load_in(Path::new(TIMESERIES_FILE))
+    {
+        Ok(t) => t,
+        Err(e) => {
+            error!("Couldn't read {}, starting the time series over: {}", TIMESERIES_FILE, e);
+            Timeseries::new()
+        }
+    });
+}
+
+/*
+Test the rollups and the file format against a scratch folder, since the public functions
+always work relative to the working dir
+*/
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    // timeseries::Timeseries::query()
+    #[test]
+    fn query_resolutions()
+    {
+        let mut timeseries = Timeseries::new();
+        let now = 1_600_000_000;
+        let counts = |a: u32, b: u32| vec![(String::from("a"), a), (String::from("b"), b)];
+        timeseries.sample(now - 2 * 86400, &counts(9, 0));
+        timeseries.sample(now - 7200, &counts(5, 1));
+        timeseries.sample(now - 2, &counts(1, 2));
+        timeseries.sample(now, &counts(3, 0));
+        let query = |video: Option<&str>, from: i64, step: Option<i64>| timeseries.query(&TimeseriesQuery{video: video.map(String::from), from: Some(from), to: Some(now), step}, now);
+
+        //the last hour is there by the second, and steps add the samples in them up
+        let recent = query(Some("a"), now - 10, None).unwrap().unwrap();
+        assert_eq!(recent.step, 1);
+        assert_eq!(recent.points.iter().map(|p| p.max).collect::<Vec<u32>>(), vec![1, 3]);
+        let minute = query(Some("b"), now - 30, Some(60)).unwrap().unwrap();
+        assert_eq!((minute.points.len(), minute.points[0].max, minute.points[0].mean), (1, 2, 1.0));
+        assert_eq!(query(None, now - 30, Some(60)).unwrap().unwrap().points[0].max, 3);
+
+        //further back, it's only there by the minute, or by the hour
+        let day = query(Some("a"), now - 3 * 3600, Some(1)).unwrap().unwrap();
+        assert_eq!((day.step, day.points.len()), (60, 2));
+        let month = query(Some("a"), now - 3 * 86400, None).unwrap().unwrap();
+        assert_eq!((month.step, month.points[0].max), (3600, 9));
+
+        assert!(query(Some("nope"), now - 10, None).unwrap().is_none());
+        assert!(query(Some("a"), now + 10, None).is_err());
+        assert!(query(Some("a"), now - 10, Some(0)).is_err());
+        assert!(query(Some("a"), now - 86400, Some(1)).is_ok());
+        assert!(query(Some("a"), 0, Some(60)).is_err());
+
+        //ranges too wide to work with are turned away rather than overflowing
+        let extreme = |from: i64, to: i64, step: Option<i64>| timeseries.query(&TimeseriesQuery{video: Some(String::from("a")), from: Some(from), to: Some(to), step}, now);
+        assert!(extreme(i64::MIN, i64::MAX, None).is_err());
+        assert!(extreme(i64::MIN, i64::MAX, Some(i64::MAX)).is_err());
+        assert!(extreme(i64::MIN, i64::MIN + 10, Some(3600)).is_err());
+        assert!(extreme(i64::MAX, i64::MIN, None).is_err());
+    }
+
+    // timeseries::save() and the loading of TIMESERIES
+    #[test]
+    fn save_and_load()
+    {
+        let dir = std::env::temp_dir().join(format!("metric_videos_timeseries_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(TIMESERIES_FILE);
+        assert!(load_in(&path).unwrap().series.is_empty());
+
+        let mut timeseries = Timeseries::new();
+        timeseries.sample(1_600_000_000, &[(String::from("a"), 4)]);
+        timeseries.sample(1_600_000_030, &[(String::from("a"), 2)]);
+        save_in(&path, &serde_json::to_string(&timeseries.saved()).unwrap()).unwrap();
+
+        let query = TimeseriesQuery{video: Some(String::from("a")), from: Some(1_599_990_000), ..TimeseriesQuery::default()};
+        let loaded = load_in(&path).unwrap();
+        assert_eq!(loaded.query(&query, 1_600_000_060), timeseries.query(&query, 1_600_000_060));
+
+        fs::write(&path, "not json").unwrap();
+        assert!(load_in(&path).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,70 +1,153 @@
+use actix_rt::time::delay_for;
 use chrono::Utc;
-use log::{/*error, warn, info, debug,*/ trace, /*log, Level*/};
+use log::{error, warn, info, /*debug,*/ trace, /*log, Level*/};
 
-use std::net::IpAddr;
-use std::thread;
 use std::time::Duration;
 
-use crate::metrics::METRICS;
+use crate::events;
+use crate::history;
+use crate::metrics::{self, apply_settings, METRICS};
+use crate::settings::{ConfigWatcher, SETTINGS, Settings};
+use crate::summary::{self, PEAKS};
+use crate::timeseries::{self, TIMESERIES};
+
+/// How often the time series and the day's peaks are saved to disk, in seconds. Shutting down saves them too, so this only limits what a crash loses.
+const SAVE_INTERVAL_SECS: i64 = 60;
 
 /**
-Start the updater loop that will run forever, waiting a few seconds between each attempt to update.
-It is up to the caller to run this in a separate thread, or be blocked indefinitely.
+Run the updater loop until the app shuts down, waiting `prune_interval_secs` from the `[metrics]` config between each update.
+It is meant to be spawned as a task on the same runtime as the HTTP server, which drops it once the server has stopped;
+call `shutdown` after that to flush what it would have got to later.
+
+Each update prunes watchers that have gone quiet, samples how many watchers each video has for the time series
+and the day's peaks, and reloads the config file if it has changed.
+Updates don't await anything, so the task can only be dropped between them, never partway through one.
 
 # Errors
-On most errors it will simply wait the usual interval before trying again.
-On serious errors likely to happen again every time, it will terminate.
-In either case, it will log what went wrong.
+On errors it will simply wait the usual interval before trying again, logging what went wrong.
 
 # Examples
 ```no_run
 use metric_videos::updater;
-use std::thread;
-//Keep the DB updated while the app runs
-thread::spawn(|| { updater::updater(); });
+# async fn run(server: actix_web::dev::Server) -> std::io::Result<()> {
+//Keep the metrics updated while the server runs, then flush them
+actix_rt::spawn(updater::updater());
+server.await?;
+updater::shutdown();
+# Ok(())
+# }
 ```
 */
-pub fn updater()
+pub async fn updater()
 {
-    let mut first_iter = true;
+    let mut config_watcher = ConfigWatcher::new();
+    let mut saved_at = Utc::now().timestamp();
     loop{
-        /* Wait a few seconds between iterations.
-        We have this first_iter guard to start immediately the first time,
-        which wouldn't be necessary if we just put the sleep at the end of the loop instead,
-        but doing it this way allows using `continue` to abort bad iterations without skipping the sleep.
-        */
-        if first_iter
+        update(&mut config_watcher, &mut saved_at);
+
+        //never less than a second, so a zero in the config can't make this spin
+        delay_for(Duration::from_secs(u64::from(SETTINGS.get().metrics.prune_interval_secs.max(1)))).await;
+    }
+}
+
+/**
+Flush everything the updater would otherwise have got to later, for when the app is shutting down.
+Every watcher still in the live metrics is ended and recorded in the history, as if they'd all just gone quiet,
+and the time series and the day's peaks are saved.
+*/
+pub fn shutdown()
+{
+    let ended = METRICS.drain();
+    match history::record(&ended)
+    {
+        Ok(()) => info!("Recorded {} session(s) still open at shutdown", ended.len()),
+        Err(e) => error!("Couldn't record {} session(s) still open at shutdown: {}", ended.len(), e)
+    }
+    if let Err(e) = timeseries::save()
+    {
+        error!("Couldn't save the time series at shutdown: {}", e);
+    }
+    if let Err(e) = summary::save()
+    {
+        error!("Couldn't save the day's peaks at shutdown: {}", e);
+    }
+}
+
+/// One iteration of the updater loop. `saved_at` is when the time series and peaks were last saved.
+fn update(config_watcher: &mut ConfigWatcher, saved_at: &mut i64)
+{
+    trace!("Iterating periodic update loop");
+
+    if config_watcher.changed()
+    {
+        reload_settings();
+    }
+
+    // Prune any watchers that haven't updated within the timeout
+    let now = Utc::now().timestamp();
+    let timeout = SETTINGS.get().metrics.watcher_timeout_secs;
+    let ended = METRICS.prune(now, i64::from(timeout));
+
+    // Count who's left for the time series and peaks
+    let counts = METRICS.counts();
+    timeseries::lock(&TIMESERIES).sample(now, &counts);
+    let finished_day = summary::lock(&PEAKS).sample(now, &counts);
+
+    events::ping();
+    if events::take_dropped()
+    {
+        warn!("Dashboards fell behind on events, resyncing them");
+        metrics::resync_dashboards();
+    }
+
+    // Their sessions go to the history store, outside the locks so disk I/O doesn't hold up reports
+    if let Err(e) = history::record(&ended)
+    {
+        error!("Couldn't record {} session(s) to history: {}", ended.len(), e);
+    }
+    if let Some((day, peaks)) = finished_day
+    {
+        if let Err(e) = summary::save_peaks(day, &peaks)
+        {
+            error!("Couldn't save the peaks of {}: {}", day, e);
+        }
+    }
+    if now - *saved_at >= SAVE_INTERVAL_SECS
+    {
+        *saved_at = now;
+        if let Err(e) = timeseries::save()
+        {
+            error!("Couldn't save the time series: {}", e);
+        }
+        if let Err(e) = summary::save()
         {
-            first_iter = false;
-        }else{
-            thread::sleep(Duration::from_secs(2));
+            error!("Couldn't save the day's peaks: {}", e);
         }
+    }
+}
 
-        trace!("Iterating periodic update loop");
+/**
+Load the config file again and put it into effect, bringing the live metrics in line with the new video catalog.
+If the new config has any problems, the old one stays in effect.
+*/
+fn reload_settings()
+{
+    let new = match Settings::reload()
+    {
+        Ok(s) => s,
+        Err(e) => {
+            error!("Config file changed, but keeping the old config: {}", e);
+            return;
+        }
+    };
 
-        // Prune any watchers that haven't updated in the last 5 seconds
-        match METRICS.try_lock()
-        {
-            Ok(mut g) =>
-            {
-                let now = Utc::now().timestamp();
-                for vid_index in 0 .. g.videos.len()
-                {
-                    let mut ips_to_remove: Vec<IpAddr> = Vec::new();
-                    for (ip,watcher) in &g.videos[vid_index].watchers
-                    {
-                        if now - watcher.when > 5
-                        {
-                            ips_to_remove.push(*ip);
-                        }
-                    }
-                    for ip in ips_to_remove
-                    {
-                        g.videos[vid_index].watchers.remove(&ip);
-                    }
-                }
-            },
-            Err(_) => {continue;}
-        };
+    let old = SETTINGS.get();
+    if old.startup.working_dir != new.startup.working_dir || old.startup.listen_addr != new.startup.listen_addr
+    {
+        warn!("Changes to the [startup] section of the config only take effect after a restart");
     }
-}
\ No newline at end of file
+    let video_count = new.media.videos.len();
+
+    apply_settings(new);
+    info!("Reloaded config, now offering {} video(s)", video_count);
+}